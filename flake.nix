@@ -49,9 +49,11 @@
             ];
             overrideMain = _: {
               postPatch = ''
+                substituteInPlace ./src/resolver.rs \
+                  --replace-fail 'Command::new("nix-locate")' 'Command::new("${lib.getExe' nix-index-unwrapped "nix-locate"}")'
+                substituteInPlace ./src/nix.rs \
+                  --replace-fail '"nix"' '"${lib.getExe nix}"'
                 substituteInPlace ./src/main.rs \
-                  --replace-fail '"nix-locate"' '"${lib.getExe' nix-index-unwrapped "nix-locate"}"' \
-                  --replace-fail '"nix"' '"${lib.getExe nix}"' \
                   --replace-fail '"nix-env"' '"${lib.getExe' nix "nix-env"}"' \
                   --replace-fail '"fzy"' '"${lib.getExe fzy}"'
               '';
@@ -1,73 +1,1002 @@
-use std::{collections::HashMap, error::Error, fs, path::PathBuf};
+use std::{
+    collections::HashMap,
+    error::Error,
+    fmt,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
-use bitcode::{Decode, Encode};
+mod format;
+
+use format::{Entries, Misses, Stored};
 use log::{debug, error};
+use serde::{Deserialize, Serialize};
 
-#[derive(Encode, Decode)]
-struct CacheData(HashMap<String, CacheEntry>);
+use crate::nix::{self, Source};
 
-#[derive(Encode, Decode, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct CacheEntry {
     pub derivation: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
     pub path: Option<String>,
+    /// Pinned entries are kept when the cache is emptied
+    #[serde(default)]
+    pub pinned: bool,
+    /// When the path was resolved, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub resolved_at: Option<u64>,
+    /// Revision of nixpkgs the path was built from, if known
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    /// Version of the package, taken from the name of its store path
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    /// How the package was chosen
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub method: Option<Method>,
+    /// When the package was chosen, in seconds since the Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chosen_at: Option<u64>,
+    /// When the entry was last used to run the command, in seconds since the
+    /// Unix epoch
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<u64>,
+    /// Every package the choice was made from
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub candidates: Option<Candidates>,
+}
+
+/// The packages found for a command, by the resolvers named `resolvers` in
+/// the nix-index database identified by `database`. The database is `None`
+/// unless the resolvers only depend on it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct Candidates {
+    pub attributes: Vec<String>,
+    pub resolvers: String,
+    pub database: Option<String>,
+    /// Store paths of the packages, where the resolvers know them
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub store_paths: HashMap<String, String>,
+}
+
+impl Candidates {
+    /// Whether the same resolvers would find the same packages in the current
+    /// database. Without a database there is no telling.
+    pub fn is_current(&self, resolvers: &str, database: Option<&str>) -> bool {
+        self.resolvers == resolvers
+            && self.database.is_some()
+            && self.database.as_deref() == database
+    }
+}
+
+/// How the package of a cache entry was chosen
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Method {
+    /// Picked among the candidates
+    Picker,
+    /// The only candidate
+    Single,
+    /// Configured in the `[packages]` table
+    Config,
+    /// Configured by the project file
+    Project,
+    /// Set with `comma cache set`
+    Manual,
+    /// Taken from [`SYSTEM_CACHE_FILE`]
+    System,
+    /// The first candidate, chosen without asking
+    First,
+    /// The best ranked candidate, chosen without asking
+    Heuristic,
+}
+
+impl fmt::Display for Method {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Method::Picker => "picker",
+            Method::Single => "single",
+            Method::Config => "config",
+            Method::Project => "project",
+            Method::Manual => "manual",
+            Method::System => "system",
+            Method::First => "first",
+            Method::Heuristic => "heuristic",
+        })
+    }
+}
+
+/// The nixpkgs source and system cached choices and paths belong to, so that
+/// switching between them never reuses a choice or path made for another
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct Scope {
+    pub source: Source,
+    pub system: String,
+}
+
+impl Scope {
+    /// Scope of `source` on the current system
+    pub fn new(source: Source) -> Self {
+        Self {
+            source,
+            system: nix::current_system(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct CacheKey {
+    pub command: String,
+    pub scope: Scope,
+}
+
+/// A command that the resolvers named `resolvers` did not find
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct MissKey {
+    pub command: String,
+    pub resolvers: String,
+}
+
+impl CacheEntry {
+    pub fn new(derivation: String) -> Self {
+        Self {
+            derivation,
+            path: None,
+            pinned: false,
+            resolved_at: None,
+            revision: None,
+            version: None,
+            method: None,
+            chosen_at: None,
+            last_used: None,
+            candidates: None,
+        }
+    }
+
+    /// Entry for `derivation`, chosen now with `method`
+    pub fn chosen(derivation: String, method: Method) -> Self {
+        Self {
+            method: Some(method),
+            chosen_at: Some(now()),
+            ..Self::new(derivation)
+        }
+    }
+
+    /// The same choice of package, without the path resolved for it
+    pub fn without_path(&self) -> Self {
+        Self {
+            path: None,
+            resolved_at: None,
+            revision: None,
+            version: None,
+            ..self.clone()
+        }
+    }
+
+    /// When the entry was last used, or else when it was last updated
+    pub fn used_at(&self) -> Option<u64> {
+        self.last_used.or(self.resolved_at).or(self.chosen_at)
+    }
+
+    /// Whether the path was resolved longer than `ttl` ago. Paths resolved
+    /// before their age was recorded are expired as soon as a TTL is set.
+    pub fn is_expired(&self, ttl: Ttl) -> bool {
+        let Ttl::After(ttl) = ttl else {
+            return false;
+        };
+        self.path.is_some()
+            && self
+                .resolved_at
+                .is_none_or(|resolved_at| now().saturating_sub(resolved_at) >= ttl.as_secs())
+    }
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+const TTL_UNITS: [(char, u64); 4] = [('d', 86400), ('h', 3600), ('m', 60), ('s', 1)];
+
+/// How long a cached path is used before it is resolved again, written as
+/// `never` or a number with a unit, e.g. `12h` or `7d`
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Ttl {
+    Never,
+    After(Duration),
+}
+
+impl FromStr for Ttl {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "never" {
+            return Ok(Ttl::Never);
+        }
+        let invalid = || format!("invalid TTL '{s}', expected 'never' or e.g. 30m, 12h, 7d");
+        let unit = s.chars().next_back().ok_or_else(invalid)?;
+        let (_, secs) = TTL_UNITS
+            .iter()
+            .find(|(u, _)| *u == unit)
+            .ok_or_else(invalid)?;
+        let count: u64 = s[..s.len() - 1].parse().map_err(|_| invalid())?;
+        let secs = count.checked_mul(*secs).ok_or_else(invalid)?;
+        Ok(Ttl::After(Duration::from_secs(secs)))
+    }
+}
+
+impl TryFrom<String> for Ttl {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// Written with the largest unit that divides it
+impl fmt::Display for Ttl {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Ttl::After(ttl) = self else {
+            return f.write_str("never");
+        };
+        let secs = ttl.as_secs();
+        let (unit, size) = TTL_UNITS
+            .iter()
+            .find(|(_, size)| secs % size == 0)
+            .unwrap_or(&('s', 1));
+        write!(f, "{}{unit}", secs / size)
+    }
+}
+
+/// When an expired path is resolved again
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Refresh {
+    /// Before running the command
+    Foreground,
+    /// While the command runs with the expired path, for the next run
+    Background,
+}
+
+impl FromStr for Refresh {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "foreground" => Ok(Refresh::Foreground),
+            "background" => Ok(Refresh::Background),
+            _ => Err(format!(
+                "unknown refresh mode '{s}', expected foreground or background"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Refresh {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Refresh {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Refresh::Foreground => f.write_str("foreground"),
+            Refresh::Background => f.write_str("background"),
+        }
+    }
+}
+
+/// An entry as exported, with the command and scope it belongs to
+#[derive(Serialize, Deserialize)]
+pub struct ExportedEntry {
+    pub command: String,
+    #[serde(flatten)]
+    pub scope: Scope,
+    #[serde(flatten)]
+    pub entry: CacheEntry,
+}
+
+/// Entries as written by `comma cache export`, and read from
+/// [`SYSTEM_CACHE_FILE`]
+#[derive(Serialize, Deserialize)]
+pub struct Exported {
+    pub entries: Vec<ExportedEntry>,
+}
+
+/// Choices shared by every user, in the format of `comma cache export`. The
+/// per-user cache takes precedence, and this file is never written.
+pub const SYSTEM_CACHE_FILE: &str = "/etc/comma/choices.toml";
+
+/// Where the entry of a command comes from
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Layer {
+    /// The user's cache
+    User,
+    /// [`SYSTEM_CACHE_FILE`]
+    System,
+}
+
+impl fmt::Display for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Layer::User => f.write_str("user"),
+            Layer::System => f.write_str("system"),
+        }
+    }
+}
+
+/// How the whole cache was emptied since it was read
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Reset {
+    None,
+    /// Entries that are not pinned and all misses were deleted
+    Unpinned,
+    /// Every entry and miss was deleted
+    All,
 }
 
 pub struct Cache {
     path: PathBuf,
-    data: CacheData,
-    update: bool,
+    /// Scope of the commands queried and updated
+    scope: Scope,
+    data: Entries,
+    /// Read-only entries from [`SYSTEM_CACHE_FILE`], which the project cache
+    /// does without
+    system: Entries,
+    /// Entries changed since the cache was read, `None` for deleted ones.
+    /// Only these are written back, so concurrent invocations changing other
+    /// commands do not overwrite each other.
+    changes: HashMap<CacheKey, Option<CacheEntry>>,
+    misses: Misses,
+    /// Misses changed since the cache was read, `None` for forgotten ones
+    miss_changes: HashMap<MissKey, Option<String>>,
+    reset: Reset,
+    /// Whether paths are protected from garbage collection
+    gc_roots: bool,
+    /// Where the GC roots of this cache's paths go, apart from other caches'
+    gc_roots_dir: PathBuf,
+    read_only: bool,
+    warnings: Vec<String>,
+}
+
+/// Lock the cache file at `path` against changes by other invocations, until
+/// the returned file is dropped
+fn lock(path: &Path) -> io::Result<File> {
+    let lock = File::create(path.with_extension("lock"))?;
+    lock.lock()?;
+    Ok(lock)
+}
+
+/// Decode the cache file, which is empty if it does not exist
+fn decode(path: &Path, scope: &Scope) -> io::Result<Result<Stored, format::Error>> {
+    match fs::read(path) {
+        Ok(bytes) => Ok(format::decode(&bytes, scope)),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Ok(Stored::default())),
+        Err(e) => Err(e),
+    }
+}
+
+/// Read the cache file. An unreadable file is moved aside so that caching
+/// keeps working, unless `read_only`, which is added to `warnings`. Only a file
+/// written by a newer version of comma is always an error. Entries from before
+/// the cache was scoped are assumed to belong to `scope`.
+fn read(
+    path: &Path,
+    scope: &Scope,
+    read_only: bool,
+    warnings: &mut Vec<String>,
+) -> Result<Stored, Box<dyn Error>> {
+    match decode(path, scope)? {
+        Err(format::Error::Corrupt(_)) if !read_only => {
+            let _lock = lock(path)?;
+            read_locked(path, scope, warnings)
+        }
+        stored => Ok(stored?),
+    }
+}
+
+/// Like [`read`], with the cache locked. The file is read again, since another
+/// invocation may have replaced or moved aside the unreadable file meanwhile.
+fn read_locked(
+    path: &Path,
+    scope: &Scope,
+    warnings: &mut Vec<String>,
+) -> Result<Stored, Box<dyn Error>> {
+    let e = match decode(path, scope)? {
+        Err(format::Error::Corrupt(e)) => e,
+        stored => return Ok(stored?),
+    };
+
+    let backup = path.with_extension("bak");
+    match fs::rename(path, &backup) {
+        Ok(()) => warnings.push(format!(
+            "the comma cache '{}' could not be read ({e}), it was moved to '{}' and a new cache was started.",
+            path.display(),
+            backup.display()
+        )),
+        // Already moved aside by another invocation
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e.into()),
+    }
+    Ok(Stored::default())
+}
+
+/// Whether the user's entry takes precedence over the system's. Copies of a
+/// system entry only do while the system entry is unchanged.
+fn shadows(user: &CacheEntry, system: &CacheEntry) -> bool {
+    user.method != Some(Method::System) || user.derivation == system.derivation
+}
+
+/// Read the system layer. A broken file is added to `warnings` and ignored,
+/// so that it does not break comma for every user.
+fn read_system(path: &Path, warnings: &mut Vec<String>) -> Entries {
+    let contents = match fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Entries::new(),
+        Err(e) => {
+            warnings.push(format!("failed to read '{}': {e}", path.display()));
+            return Entries::new();
+        }
+    };
+
+    debug!("reading system cache from {}", path.display());
+
+    match toml::from_str::<Exported>(&contents) {
+        Ok(exported) => exported
+            .entries
+            .into_iter()
+            .map(
+                |ExportedEntry {
+                     command,
+                     scope,
+                     entry,
+                 }| {
+                    let entry = CacheEntry {
+                        method: Some(Method::System),
+                        ..entry
+                    };
+                    (CacheKey { command, scope }, entry)
+                },
+            )
+            .collect(),
+        Err(e) => {
+            warnings.push(format!("failed to parse '{}': {e}", path.display()));
+            Entries::new()
+        }
+    }
 }
 
 impl Cache {
-    pub fn new() -> Result<Self, Box<dyn Error>> {
+    /// Open the cache for commands in `scope`
+    pub fn new(scope: Scope) -> Result<Self, Box<dyn Error>> {
         let path = xdg::BaseDirectories::new()?.place_state_file("comma/choices")?;
+        Self::open(
+            path,
+            "gcroots",
+            Some(Path::new(SYSTEM_CACHE_FILE)),
+            scope,
+            false,
+        )
+    }
 
+    /// Open the cache for commands in `scope` without ever changing it on
+    /// disk. Changes are only seen by this instance.
+    pub fn new_read_only(scope: Scope) -> Result<Self, Box<dyn Error>> {
+        let path = xdg::BaseDirectories::new()?
+            .get_state_home()
+            .join("comma/choices");
+        Self::open(
+            path,
+            "gcroots",
+            Some(Path::new(SYSTEM_CACHE_FILE)),
+            scope,
+            true,
+        )
+    }
+
+    /// Open the cache of paths for commands pinned by project files, which is
+    /// kept apart so that projects never change the user's choices
+    pub fn new_project(scope: Scope) -> Result<Self, Box<dyn Error>> {
+        let path = xdg::BaseDirectories::new()?.place_state_file("comma/project-choices")?;
+        Self::open(path, "project-gcroots", None, scope, false)
+    }
+
+    /// Like [`Cache::new_project`], without ever changing it on disk
+    pub fn new_project_read_only(scope: Scope) -> Result<Self, Box<dyn Error>> {
+        let path = xdg::BaseDirectories::new()?
+            .get_state_home()
+            .join("comma/project-choices");
+        Self::open(path, "project-gcroots", None, scope, true)
+    }
+
+    /// Open the cache file at `path`, with GC roots in the directory named
+    /// `gc_roots_dir` next to it, on top of the `system` layer if any
+    fn open(
+        path: PathBuf,
+        gc_roots_dir: &str,
+        system: Option<&Path>,
+        scope: Scope,
+        read_only: bool,
+    ) -> Result<Self, Box<dyn Error>> {
         debug!("creating new cache instance for path: {}", path.display());
 
+        let mut warnings = Vec::new();
+        let Stored { entries, misses } = read(&path, &scope, read_only, &mut warnings)?;
+
         Ok(Self {
-            data: if path.exists() {
-                let bytes = fs::read(&path)?;
-                bitcode::decode(&bytes)?
-            } else {
-                CacheData(HashMap::new())
-            },
+            gc_roots_dir: path.with_file_name(gc_roots_dir),
+            data: entries,
+            system: system.map_or_else(Entries::new, |system| read_system(system, &mut warnings)),
             path,
-            update: false,
+            scope,
+            changes: HashMap::new(),
+            misses,
+            miss_changes: HashMap::new(),
+            reset: Reset::None,
+            gc_roots: false,
+            read_only,
+            warnings,
         })
     }
 
+    /// Problems met while opening the cache that did not prevent using it,
+    /// for the caller to report
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Protect the paths resolved for the cache from garbage collection with
+    /// indirect GC roots in the comma state directory
+    pub fn set_gc_roots(&mut self, enabled: bool) {
+        self.gc_roots = enabled;
+    }
+
+    /// File name of the GC root for the path of `key`
+    fn gc_root_name(key: &CacheKey) -> String {
+        format!("{}-{}-{}", key.command, key.scope.system, key.scope.source)
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || "+-._".contains(c) {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect()
+    }
+
+    /// Where the GC root for the path of `command` goes, if GC roots are
+    /// enabled
+    pub fn gc_root(&self, command: &str) -> Option<PathBuf> {
+        if !self.gc_roots {
+            return None;
+        }
+        if let Err(e) = fs::create_dir_all(&self.gc_roots_dir) {
+            error!("failed to create '{}': {e}", self.gc_roots_dir.display());
+            return None;
+        }
+        Some(
+            self.gc_roots_dir
+                .join(Self::gc_root_name(&self.key(command))),
+        )
+    }
+
+    /// Remove the GC roots of entries not used within `unused_for`, and of
+    /// entries that no longer exist. Returns the removed roots.
+    pub fn drop_gc_roots(&self, unused_for: Duration) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+        let dir = &self.gc_roots_dir;
+        if !dir.exists() {
+            return Ok(Vec::new());
+        }
+
+        let cutoff = now().saturating_sub(unused_for.as_secs());
+        let used: HashMap<_, _> = self
+            .data
+            .iter()
+            .map(|(key, entry)| (Self::gc_root_name(key), entry.used_at().unwrap_or_default()))
+            .collect();
+
+        let mut dropped = Vec::new();
+        for root in fs::read_dir(dir)? {
+            let root = root?;
+            let keep = root
+                .file_name()
+                .to_str()
+                .and_then(|name| used.get(name))
+                .is_some_and(|used_at| *used_at >= cutoff);
+            if !keep {
+                debug!("removing GC root {}", root.path().display());
+                fs::remove_file(root.path())?;
+                dropped.push(root.path());
+            }
+        }
+
+        Ok(dropped)
+    }
+
+    pub fn scope(&self) -> &Scope {
+        &self.scope
+    }
+
+    fn key(&self, command: &str) -> CacheKey {
+        CacheKey {
+            command: command.into(),
+            scope: self.scope.clone(),
+        }
+    }
+
     pub fn query(&self, command: &str) -> Option<CacheEntry> {
+        self.query_layer(command).map(|(entry, _)| entry)
+    }
+
+    /// The entry of `command` and the layer it comes from. The user's entry
+    /// wins, unless it was copied from a system entry that has changed since.
+    pub fn query_layer(&self, command: &str) -> Option<(CacheEntry, Layer)> {
         debug!("querying cache entry for command '{command}'");
-        self.data.0.get(command).cloned()
+        let key = self.key(command);
+        match (self.data.get(&key), self.system.get(&key)) {
+            (Some(user), Some(system)) if !shadows(user, system) => {
+                Some((system.clone(), Layer::System))
+            }
+            (Some(user), _) => Some((user.clone(), Layer::User)),
+            (None, system) => Some((system?.clone(), Layer::System)),
+        }
     }
 
     pub fn update(&mut self, command: &str, entry: CacheEntry) {
-        debug!("updating cache entry for command '{command}': {entry:?}");
-        self.data.0.insert(command.into(), entry);
-        self.update = true;
+        self.insert(self.key(command), entry);
     }
 
     pub fn delete(&mut self, command: &str) {
-        debug!("deleting cache for command '{command}'");
-        self.data.0.remove(command);
-        self.update = true;
+        self.remove(self.key(command));
+    }
+
+    /// Record that the entry of `command` was used now. This is done at most
+    /// once a minute, so running a command in a loop does not rewrite the
+    /// cache every time.
+    pub fn touch(&mut self, command: &str) {
+        let Some(entry) = self.query(command) else {
+            return;
+        };
+        let now = now();
+        if entry
+            .last_used
+            .is_none_or(|last_used| now >= last_used + 60)
+        {
+            self.update(
+                command,
+                CacheEntry {
+                    last_used: Some(now),
+                    ..entry
+                },
+            );
+        }
+    }
+
+    /// Set the entry of a command in any scope
+    pub fn insert(&mut self, key: CacheKey, entry: CacheEntry) {
+        debug!("updating cache entry for {key:?}: {entry:?}");
+        self.data.insert(key.clone(), entry.clone());
+        self.changes.insert(key, Some(entry));
+    }
+
+    /// Delete the entry of a command in any scope
+    pub fn remove(&mut self, key: CacheKey) {
+        debug!("deleting cache for {key:?}");
+        self.data.remove(&key);
+        self.changes.insert(key, None);
+    }
+
+    /// Whether `resolvers` did not find `command` the last time, in the
+    /// nix-index database identified by `database`
+    pub fn is_miss(&self, command: &str, resolvers: &str, database: &str) -> bool {
+        let key = MissKey {
+            command: command.into(),
+            resolvers: resolvers.into(),
+        };
+        self.misses.get(&key).is_some_and(|d| d == database)
+    }
+
+    /// Remember that `resolvers` did not find `command` in the nix-index
+    /// database identified by `database`
+    pub fn record_miss(&mut self, command: &str, resolvers: &str, database: &str) {
+        let key = MissKey {
+            command: command.into(),
+            resolvers: resolvers.into(),
+        };
+        debug!("recording miss for {key:?}");
+        self.misses.insert(key.clone(), database.into());
+        self.miss_changes.insert(key, Some(database.into()));
     }
 
+    /// Forget that `resolvers` did not find `command`
+    pub fn forget_miss(&mut self, command: &str, resolvers: &str) {
+        let key = MissKey {
+            command: command.into(),
+            resolvers: resolvers.into(),
+        };
+        if self.misses.remove(&key).is_some() {
+            self.miss_changes.insert(key, None);
+        }
+    }
+
+    /// Delete every entry that is not pinned, and every miss
     pub fn empty(&mut self) {
         debug!("emptying cache");
-        self.data.0.clear();
-        self.update = true;
+        self.data.retain(|_, entry| entry.pinned);
+        self.changes
+            .retain(|_, change| change.as_ref().is_none_or(|entry| entry.pinned));
+        self.misses.clear();
+        self.miss_changes.clear();
+        if self.reset == Reset::None {
+            self.reset = Reset::Unpinned;
+        }
+    }
+
+    /// Delete every entry, including pinned ones, and every miss
+    pub fn clear(&mut self) {
+        debug!("clearing cache");
+        self.data.clear();
+        self.changes.clear();
+        self.misses.clear();
+        self.miss_changes.clear();
+        self.reset = Reset::All;
+    }
+
+    /// Entries of all scopes, sorted by command
+    pub fn entries(&self) -> Vec<(&CacheKey, &CacheEntry)> {
+        let mut entries: Vec<_> = self.data.iter().collect();
+        entries
+            .sort_unstable_by(|(a, _), (b, _)| (&a.command, &a.scope).cmp(&(&b.command, &b.scope)));
+        entries
+    }
+
+    /// Entries of the system layer that are not shadowed by the user's,
+    /// sorted by command
+    pub fn system_entries(&self) -> Vec<(&CacheKey, &CacheEntry)> {
+        let mut entries: Vec<_> = self
+            .system
+            .iter()
+            .filter(|(key, system)| self.data.get(key).is_none_or(|user| !shadows(user, system)))
+            .collect();
+        entries
+            .sort_unstable_by(|(a, _), (b, _)| (&a.command, &a.scope).cmp(&(&b.command, &b.scope)));
+        entries
+    }
+
+    /// Apply the changes to the cache as currently on disk. The file is
+    /// locked while doing so, and replaced atomically so that readers never
+    /// see a partially written cache.
+    fn write(&self) -> Result<(), Box<dyn Error>> {
+        let _lock = lock(&self.path)?;
+
+        let mut warnings = Vec::new();
+        let mut data = read_locked(&self.path, &self.scope, &mut warnings)?;
+        for warning in warnings {
+            error!("{warning}");
+        }
+        match self.reset {
+            Reset::None => {}
+            Reset::Unpinned => {
+                data.entries.retain(|_, entry| entry.pinned);
+                data.misses.clear();
+            }
+            Reset::All => {
+                data.entries.clear();
+                data.misses.clear();
+            }
+        }
+        for (key, change) in &self.changes {
+            match change {
+                Some(entry) => data.entries.insert(key.clone(), entry.clone()),
+                None => data.entries.remove(key),
+            };
+        }
+        for (key, change) in &self.miss_changes {
+            match change {
+                Some(database) => data.misses.insert(key.clone(), database.clone()),
+                None => data.misses.remove(key),
+            };
+        }
+
+        let tmp_path = self
+            .path
+            .with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&tmp_path, format::encode(&data))?;
+        fs::rename(&tmp_path, &self.path).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp_path);
+        })?;
+
+        Ok(())
     }
 }
 
 impl Drop for Cache {
     fn drop(&mut self) {
-        if self.update {
+        if self.read_only {
+            debug!("not writing read-only cache: {}", self.path.display());
+        } else if !self.changes.is_empty()
+            || !self.miss_changes.is_empty()
+            || self.reset != Reset::None
+        {
             debug!("writing cache to disk: {}", self.path.display());
-            let bytes = bitcode::encode(&self.data.0);
-            if let Err(e) = fs::write(&self.path, bytes) {
+            if let Err(e) = self.write() {
                 error!("failed to write cache to '{}': {e}", self.path.display());
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope() -> Scope {
+        Scope {
+            source: Source::Flake("nixpkgs".into()),
+            system: "x86_64-linux".into(),
+        }
+    }
+
+    /// Path of a cache file in a directory of its own
+    fn cache_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("comma-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir.join("choices")
+    }
+
+    fn open(path: &Path) -> Cache {
+        Cache::open(path.to_owned(), "gcroots", None, scope(), false).unwrap()
+    }
+
+    fn derivation(cache: &Cache, command: &str) -> Option<String> {
+        cache.query(command).map(|entry| entry.derivation)
+    }
+
+    #[test]
+    fn parses_ttls() {
+        assert_eq!("never".parse(), Ok(Ttl::Never));
+        for (ttl, secs) in [("30s", 30), ("5m", 300), ("12h", 43200), ("7d", 604800)] {
+            assert_eq!(ttl.parse(), Ok(Ttl::After(Duration::from_secs(secs))));
+        }
+        for invalid in ["", "30", "d", "-1d", "1w", "99999999999999999d"] {
+            assert!(invalid.parse::<Ttl>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn displays_ttls_with_the_largest_unit() {
+        for ttl in ["never", "30s", "5m", "12h", "7d", "90m"] {
+            assert_eq!(ttl.parse::<Ttl>().unwrap().to_string(), ttl);
+        }
+        assert_eq!("60m".parse::<Ttl>().unwrap().to_string(), "1h");
+        assert_eq!("48h".parse::<Ttl>().unwrap().to_string(), "2d");
+    }
+
+    #[test]
+    fn expires_paths() {
+        let hour = Ttl::After(Duration::from_secs(3600));
+        let resolved = |resolved_at| CacheEntry {
+            path: Some("/nix/store/aaaa-hello-2.12/bin/hello".into()),
+            resolved_at,
+            ..CacheEntry::new("hello.out".into())
+        };
+
+        assert!(!resolved(Some(now())).is_expired(hour));
+        assert!(resolved(Some(now() - 7200)).is_expired(hour));
+        assert!(!resolved(Some(now() - 7200)).is_expired(Ttl::Never));
+        // The age of paths from before it was recorded is unknown
+        assert!(resolved(None).is_expired(hour));
+        assert!(!resolved(None).is_expired(Ttl::Never));
+        // Without a path there is nothing to expire
+        assert!(!CacheEntry::new("hello.out".into()).is_expired(hour));
+    }
+
+    #[test]
+    fn moves_unreadable_files_aside() {
+        let path = cache_path("corrupt");
+        fs::write(&path, "garbage").unwrap();
+
+        let first = open(&path);
+        assert_eq!(first.warnings().len(), 1);
+        assert!(path.with_extension("bak").exists());
+        let second = open(&path);
+        assert!(second.warnings().is_empty());
+        assert!(Cache::open(path.clone(), "gcroots", None, scope(), true).is_ok());
+    }
+
+    #[test]
+    fn follows_changes_to_the_system_layer() {
+        let path = cache_path("system");
+        let system = path.with_file_name("choices.toml");
+        let write_system = |hello: &str| {
+            let entries = [("hello", hello), ("rg", "ripgrep.out")].map(|(command, derivation)| {
+                format!(
+                    "[[entries]]\ncommand = \"{command}\"\nsource = \"nixpkgs\"\n\
+                     system = \"x86_64-linux\"\nderivation = \"{derivation}\"\n"
+                )
+            });
+            fs::write(&system, entries.concat()).unwrap();
+        };
+        let open = || Cache::open(path.clone(), "gcroots", Some(&system), scope(), false).unwrap();
+        let layer = |cache: &Cache, command| {
+            let (entry, layer) = cache.query_layer(command).unwrap();
+            (entry.derivation, layer)
+        };
+
+        write_system("hello.out");
+        let mut cache = open();
+        assert_eq!(layer(&cache, "hello"), ("hello.out".into(), Layer::System));
+        // Using the entry copies it to the user's cache
+        cache.touch("hello");
+        cache.update(
+            "rg",
+            CacheEntry::chosen("ripgrep-all.out".into(), Method::Picker),
+        );
+        drop(cache);
+
+        write_system("hello-wayland.out");
+        let cache = open();
+        assert!(cache
+            .entries()
+            .iter()
+            .any(|(key, entry)| key.command == "hello" && entry.derivation == "hello.out"));
+        assert_eq!(
+            layer(&cache, "hello"),
+            ("hello-wayland.out".into(), Layer::System)
+        );
+        assert_eq!(layer(&cache, "rg"), ("ripgrep-all.out".into(), Layer::User));
+    }
+
+    #[test]
+    fn keeps_concurrent_changes() {
+        let path = cache_path("concurrent");
+        let mut first = open(&path);
+        let mut second = open(&path);
+
+        first.update("hello", CacheEntry::new("hello.out".into()));
+        second.update("rg", CacheEntry::new("ripgrep.out".into()));
+        drop(first);
+        drop(second);
+
+        let cache = open(&path);
+        assert_eq!(derivation(&cache, "hello").as_deref(), Some("hello.out"));
+        assert_eq!(derivation(&cache, "rg").as_deref(), Some("ripgrep.out"));
+    }
+
+    #[test]
+    fn emptying_keeps_entries_pinned_concurrently() {
+        let path = cache_path("empty");
+        let mut first = open(&path);
+        let mut second = open(&path);
+
+        first.update("hello", CacheEntry::new("hello.out".into()));
+        first.empty();
+        second.update(
+            "jq",
+            CacheEntry {
+                pinned: true,
+                ..CacheEntry::new("jq.bin".into())
+            },
+        );
+        second.update("rg", CacheEntry::new("ripgrep.out".into()));
+        drop(second);
+        drop(first);
+
+        let cache = open(&path);
+        assert_eq!(derivation(&cache, "hello"), None);
+        assert_eq!(derivation(&cache, "rg"), None);
+        assert_eq!(derivation(&cache, "jq").as_deref(), Some("jq.bin"));
+    }
+}
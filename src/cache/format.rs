@@ -0,0 +1,440 @@
+//! On-disk layout of the cache.
+//!
+//! The file starts with [`MAGIC`] and the layout version as a little-endian
+//! `u32`, followed by the bitcode-encoded contents. Files from before the header
+//! was introduced use the headerless version 1 layout.
+//!
+//! Every layout has its own copies of the types it stores, so that changing
+//! the types used by the rest of comma never changes how a file is read.
+//! Changing a layout in any way, even appending an enum variant, requires a
+//! new version, with a migration from the previous one.
+
+use std::{collections::HashMap, fmt};
+
+use bitcode::{Decode, Encode};
+use log::debug;
+
+use super::{CacheEntry, CacheKey, Candidates, Method, MissKey, Scope};
+use crate::nix::Source;
+
+const MAGIC: &[u8] = b"COMMA";
+
+/// Version of the layout written by this version of comma
+pub const CURRENT_VERSION: u32 = 2;
+
+pub type Entries = HashMap<CacheKey, CacheEntry>;
+
+/// Commands no resolver found, with the nix-index database they were looked
+/// up in
+pub type Misses = HashMap<MissKey, String>;
+
+/// Everything stored in the cache file
+#[derive(Default)]
+pub struct Stored {
+    pub entries: Entries,
+    pub misses: Misses,
+}
+
+#[derive(Debug)]
+pub enum Error {
+    /// Written by a newer version of comma, which must not be overwritten
+    Newer(u32),
+    /// Not decodable with any known layout
+    Corrupt(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Newer(version) => write!(
+                f,
+                "cache format version {version} is newer than the supported version {CURRENT_VERSION}"
+            ),
+            Error::Corrupt(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Version 1: the original layout, without a header, keyed by command only
+#[derive(Encode, Decode)]
+struct CacheDataV1(HashMap<String, CacheEntryV1>);
+
+#[derive(Encode, Decode)]
+struct CacheEntryV1 {
+    derivation: String,
+    path: Option<String>,
+}
+
+impl CacheDataV1 {
+    /// Version 1 entries have no scope, they are assumed to belong to `scope`
+    fn migrate(self, scope: &Scope) -> Stored {
+        let entries = self
+            .0
+            .into_iter()
+            .map(|(command, entry)| {
+                let key = CacheKey {
+                    command,
+                    scope: scope.clone(),
+                };
+                let entry = CacheEntry {
+                    path: entry.path,
+                    ..CacheEntry::new(entry.derivation)
+                };
+                (key, entry)
+            })
+            .collect();
+
+        Stored {
+            entries,
+            misses: Misses::new(),
+        }
+    }
+}
+
+/// Version 2: scoped entries with metadata about the choice and the path,
+/// and misses
+#[derive(Encode, Decode)]
+struct StoredV2 {
+    entries: HashMap<CacheKeyV2, CacheEntryV2>,
+    misses: HashMap<MissKeyV2, String>,
+}
+
+#[derive(Encode, Decode, PartialEq, Eq, Hash)]
+struct CacheKeyV2 {
+    command: String,
+    scope: ScopeV2,
+}
+
+#[derive(Encode, Decode, PartialEq, Eq, Hash)]
+struct ScopeV2 {
+    source: SourceV2,
+    system: String,
+}
+
+#[derive(Encode, Decode, PartialEq, Eq, Hash)]
+enum SourceV2 {
+    Channel,
+    Flake(String),
+}
+
+#[derive(Encode, Decode, PartialEq, Eq, Hash)]
+struct MissKeyV2 {
+    command: String,
+    resolvers: String,
+}
+
+#[derive(Encode, Decode)]
+struct CacheEntryV2 {
+    derivation: String,
+    path: Option<String>,
+    pinned: bool,
+    resolved_at: Option<u64>,
+    revision: Option<String>,
+    version: Option<String>,
+    method: Option<MethodV2>,
+    chosen_at: Option<u64>,
+    last_used: Option<u64>,
+    candidates: Option<CandidatesV2>,
+}
+
+#[derive(Encode, Decode)]
+enum MethodV2 {
+    Picker,
+    Single,
+    Config,
+    Project,
+    Manual,
+    System,
+    First,
+    Heuristic,
+}
+
+#[derive(Encode, Decode)]
+struct CandidatesV2 {
+    attributes: Vec<String>,
+    resolvers: String,
+    database: Option<String>,
+    store_paths: HashMap<String, String>,
+}
+
+impl From<&Stored> for StoredV2 {
+    fn from(stored: &Stored) -> Self {
+        Self {
+            entries: stored
+                .entries
+                .iter()
+                .map(|(key, entry)| (key.into(), entry.into()))
+                .collect(),
+            misses: stored
+                .misses
+                .iter()
+                .map(|(key, database)| (key.into(), database.clone()))
+                .collect(),
+        }
+    }
+}
+
+impl From<StoredV2> for Stored {
+    fn from(stored: StoredV2) -> Self {
+        Self {
+            entries: stored
+                .entries
+                .into_iter()
+                .map(|(key, entry)| (key.into(), entry.into()))
+                .collect(),
+            misses: stored
+                .misses
+                .into_iter()
+                .map(|(key, database)| (key.into(), database))
+                .collect(),
+        }
+    }
+}
+
+impl From<&CacheKey> for CacheKeyV2 {
+    fn from(key: &CacheKey) -> Self {
+        Self {
+            command: key.command.clone(),
+            scope: ScopeV2 {
+                source: match &key.scope.source {
+                    Source::Channel => SourceV2::Channel,
+                    Source::Flake(flake) => SourceV2::Flake(flake.clone()),
+                },
+                system: key.scope.system.clone(),
+            },
+        }
+    }
+}
+
+impl From<CacheKeyV2> for CacheKey {
+    fn from(key: CacheKeyV2) -> Self {
+        Self {
+            command: key.command,
+            scope: Scope {
+                source: match key.scope.source {
+                    SourceV2::Channel => Source::Channel,
+                    SourceV2::Flake(flake) => Source::Flake(flake),
+                },
+                system: key.scope.system,
+            },
+        }
+    }
+}
+
+impl From<&MissKey> for MissKeyV2 {
+    fn from(key: &MissKey) -> Self {
+        Self {
+            command: key.command.clone(),
+            resolvers: key.resolvers.clone(),
+        }
+    }
+}
+
+impl From<MissKeyV2> for MissKey {
+    fn from(key: MissKeyV2) -> Self {
+        Self {
+            command: key.command,
+            resolvers: key.resolvers,
+        }
+    }
+}
+
+impl From<&CacheEntry> for CacheEntryV2 {
+    fn from(entry: &CacheEntry) -> Self {
+        Self {
+            derivation: entry.derivation.clone(),
+            path: entry.path.clone(),
+            pinned: entry.pinned,
+            resolved_at: entry.resolved_at,
+            revision: entry.revision.clone(),
+            version: entry.version.clone(),
+            method: entry.method.map(|method| match method {
+                Method::Picker => MethodV2::Picker,
+                Method::Single => MethodV2::Single,
+                Method::Config => MethodV2::Config,
+                Method::Project => MethodV2::Project,
+                Method::Manual => MethodV2::Manual,
+                Method::System => MethodV2::System,
+                Method::First => MethodV2::First,
+                Method::Heuristic => MethodV2::Heuristic,
+            }),
+            chosen_at: entry.chosen_at,
+            last_used: entry.last_used,
+            candidates: entry.candidates.as_ref().map(|candidates| CandidatesV2 {
+                attributes: candidates.attributes.clone(),
+                resolvers: candidates.resolvers.clone(),
+                database: candidates.database.clone(),
+                store_paths: candidates.store_paths.clone(),
+            }),
+        }
+    }
+}
+
+impl From<CacheEntryV2> for CacheEntry {
+    fn from(entry: CacheEntryV2) -> Self {
+        Self {
+            derivation: entry.derivation,
+            path: entry.path,
+            pinned: entry.pinned,
+            resolved_at: entry.resolved_at,
+            revision: entry.revision,
+            version: entry.version,
+            method: entry.method.map(|method| match method {
+                MethodV2::Picker => Method::Picker,
+                MethodV2::Single => Method::Single,
+                MethodV2::Config => Method::Config,
+                MethodV2::Project => Method::Project,
+                MethodV2::Manual => Method::Manual,
+                MethodV2::System => Method::System,
+                MethodV2::First => Method::First,
+                MethodV2::Heuristic => Method::Heuristic,
+            }),
+            chosen_at: entry.chosen_at,
+            last_used: entry.last_used,
+            candidates: entry.candidates.map(|candidates| Candidates {
+                attributes: candidates.attributes,
+                resolvers: candidates.resolvers,
+                database: candidates.database,
+                store_paths: candidates.store_paths,
+            }),
+        }
+    }
+}
+
+/// Decode a cache file, migrating older layouts to the current one. Entries
+/// from before the cache was scoped are assumed to belong to `scope`.
+pub fn decode(bytes: &[u8], scope: &Scope) -> Result<Stored, Error> {
+    let Some(rest) = bytes.strip_prefix(MAGIC) else {
+        debug!("decoding headerless cache format version 1");
+        let data: CacheDataV1 = bitcode::decode(bytes)
+            .map_err(|e| Error::Corrupt(format!("invalid version 1 cache: {e}")))?;
+        return Ok(data.migrate(scope));
+    };
+
+    let (version, payload) = rest
+        .split_first_chunk()
+        .ok_or_else(|| Error::Corrupt("truncated cache header".into()))?;
+    let version = u32::from_le_bytes(*version);
+
+    debug!("decoding cache format version {version}");
+
+    match version {
+        CURRENT_VERSION => bitcode::decode::<StoredV2>(payload)
+            .map(Stored::from)
+            .map_err(|e| Error::Corrupt(format!("invalid version {version} cache: {e}"))),
+        version if version > CURRENT_VERSION => Err(Error::Newer(version)),
+        version => Err(Error::Corrupt(format!(
+            "unknown cache format version {version}"
+        ))),
+    }
+}
+
+/// Encode the contents with the current layout
+pub fn encode(stored: &Stored) -> Vec<u8> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.extend(CURRENT_VERSION.to_le_bytes());
+    bytes.extend(bitcode::encode(&StoredV2::from(stored)));
+    bytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scope() -> Scope {
+        Scope {
+            source: Source::Flake("nixpkgs".into()),
+            system: "x86_64-linux".into(),
+        }
+    }
+
+    #[test]
+    fn decodes_headerless_files() {
+        let data = CacheDataV1(HashMap::from([
+            (
+                "hello".to_owned(),
+                CacheEntryV1 {
+                    derivation: "hello.out".into(),
+                    path: Some("/nix/store/aaaa-hello-2.12/bin/hello".into()),
+                },
+            ),
+            (
+                "rg".to_owned(),
+                CacheEntryV1 {
+                    derivation: "ripgrep.out".into(),
+                    path: None,
+                },
+            ),
+        ]));
+
+        let stored = decode(&bitcode::encode(&data), &scope()).unwrap();
+
+        assert!(stored.misses.is_empty());
+        assert_eq!(stored.entries.len(), 2);
+        let key = |command: &str| CacheKey {
+            command: command.into(),
+            scope: scope(),
+        };
+        let hello = &stored.entries[&key("hello")];
+        assert_eq!(hello.derivation, "hello.out");
+        assert_eq!(
+            hello.path.as_deref(),
+            Some("/nix/store/aaaa-hello-2.12/bin/hello")
+        );
+        assert!(!hello.pinned);
+        assert_eq!(hello.method, None);
+        let rg = &stored.entries[&key("rg")];
+        assert_eq!(rg.derivation, "ripgrep.out");
+        assert_eq!(rg.path, None);
+    }
+
+    #[test]
+    fn round_trips_the_current_layout() {
+        let key = CacheKey {
+            command: "hello".into(),
+            scope: scope(),
+        };
+        let entry = CacheEntry {
+            pinned: true,
+            method: Some(Method::Heuristic),
+            candidates: Some(Candidates {
+                attributes: vec!["hello.out".into(), "hello-wayland.out".into()],
+                resolvers: "database".into(),
+                database: Some("1700000000".into()),
+                store_paths: HashMap::from([(
+                    "hello.out".into(),
+                    "/nix/store/hhello-hello-1.0".into(),
+                )]),
+            }),
+            ..CacheEntry::new("hello.out".into())
+        };
+        let miss = MissKey {
+            command: "nope".into(),
+            resolvers: "database".into(),
+        };
+        let stored = Stored {
+            entries: HashMap::from([(key.clone(), entry)]),
+            misses: HashMap::from([(miss.clone(), "1700000000".into())]),
+        };
+
+        let decoded = decode(&encode(&stored), &scope()).unwrap();
+
+        let entry = &decoded.entries[&key];
+        assert!(entry.pinned);
+        assert_eq!(entry.method, Some(Method::Heuristic));
+        assert_eq!(entry.candidates, stored.entries[&key].candidates);
+        assert_eq!(decoded.misses[&miss], "1700000000");
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend((CURRENT_VERSION + 1).to_le_bytes());
+        assert!(matches!(
+            decode(&bytes, &scope()),
+            Err(Error::Newer(version)) if version == CURRENT_VERSION + 1
+        ));
+    }
+}
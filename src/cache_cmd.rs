@@ -0,0 +1,655 @@
+use std::{
+    collections::HashMap,
+    error::Error,
+    fs,
+    io::{self, Read},
+    path::{Path, PathBuf},
+    process::ExitCode,
+    time::Duration,
+};
+
+use clap::{Args, Subcommand, ValueEnum};
+use comma::{
+    cache::{self, CacheKey, Exported, ExportedEntry, Layer, Method, Scope, SYSTEM_CACHE_FILE},
+    database::{self, Package},
+    index, resolver,
+    usage::{Usage, UsageLog},
+    Cache, CacheEntry,
+};
+use serde::Serialize;
+
+#[derive(Args)]
+pub struct CacheArgs {
+    /// Print JSON instead of a table
+    #[clap(long, global = true)]
+    json: bool,
+
+    #[clap(subcommand)]
+    cmd: CacheCmd,
+}
+
+#[derive(Subcommand)]
+enum CacheCmd {
+    /// List all cached commands
+    List,
+    /// Show the cache entry of a command
+    Show { cmd: String },
+    /// Choose the package used for a command
+    Set { cmd: String, derivation: String },
+    /// Keep the entry of a command when the cache is emptied
+    Pin { cmd: String },
+    /// Stop keeping the entry of a command when the cache is emptied
+    Unpin { cmd: String },
+    /// Remove the entry of a command
+    #[clap(visible_alias = "remove")]
+    Rm { cmd: String },
+    /// Remove stale paths and entries
+    ///
+    /// Paths that no longer exist are removed from all entries. Choices for
+    /// the current nixpkgs among packages found in the nix-index database are
+    /// removed if the database no longer has them. Choices found by mapping or
+    /// command resolvers are not checked. Pinned entries are always kept.
+    Prune {
+        /// Also remove entries not used within this many days
+        #[clap(long)]
+        unused_days: Option<u64>,
+        /// Print what would be removed without changing the cache
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Remove the GC roots of cached paths that were not used recently
+    ///
+    /// GC roots are created with --gc-roots. Once removed, the paths can be
+    /// garbage collected again.
+    Gc {
+        /// Keep the roots of paths used within this many days
+        #[clap(long, default_value_t = 30)]
+        days: u64,
+    },
+    /// Show how often each command was run, and from which package
+    ///
+    /// Commands are sorted by frecency: their number of runs, weighted by how
+    /// recently they last ran. Frequently used commands may be worth
+    /// installing permanently.
+    Stats {
+        /// Only show the most used commands
+        #[clap(short = 'n', long)]
+        limit: Option<usize>,
+    },
+    /// Write all entries in a human-readable format
+    Export {
+        #[clap(long, value_enum, default_value_t = Format::Json)]
+        format: Format,
+        /// Leave out store paths, keeping only the choices of packages
+        #[clap(long)]
+        strip_paths: bool,
+        /// File to write to instead of stdout
+        #[clap(short, long)]
+        output: Option<PathBuf>,
+    },
+    /// Read entries written by `comma cache export`
+    Import {
+        /// File to read, stdin if omitted or `-`
+        file: Option<PathBuf>,
+        /// Format of the file, guessed from its extension if omitted
+        #[clap(long, value_enum)]
+        format: Option<Format>,
+        #[clap(long, value_enum, default_value_t = Strategy::Merge)]
+        strategy: Strategy,
+        /// Leave out store paths, keeping only the choices of packages, and
+        /// use them on this system whatever system they were exported from
+        #[clap(long)]
+        strip_paths: bool,
+    },
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Format {
+    Json,
+    Toml,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Strategy {
+    /// Add the imported entries, replacing existing ones for the same commands
+    Merge,
+    /// Replace the whole cache with the imported entries
+    Replace,
+}
+
+/// What pruning does to an entry
+enum Prune {
+    Path(String),
+    Choice,
+    Unused(u64),
+}
+
+fn prune(cache: &mut Cache, unused_days: Option<u64>, dry_run: bool) {
+    let scope = cache.scope().clone();
+    let entries = cache.entries();
+
+    // Only the choices for the current nixpkgs can be checked against the
+    // database, and only those that came from it. Entries without a method
+    // predate the other resolvers.
+    let from_database = |entry: &CacheEntry| match (&entry.method, &entry.candidates) {
+        (None, _) => true,
+        (
+            Some(Method::Picker | Method::Single | Method::First | Method::Heuristic),
+            Some(candidates),
+        ) => resolver::is_database_chain(&candidates.resolvers),
+        _ => false,
+    };
+    let checked: Vec<_> = entries
+        .iter()
+        .filter(|(key, entry)| key.scope == scope && !entry.pinned && from_database(entry))
+        .map(|(key, _)| key.command.as_str())
+        .collect();
+    let located = if checked.is_empty() {
+        None
+    } else {
+        database::locate_all(&index::get_database_file(), &checked)
+            .inspect_err(|e| eprintln!("Warning: not checking choices against the database: {e}"))
+            .ok()
+    };
+
+    let unused_since = unused_days.map(|days| {
+        let cutoff = cache::now().saturating_sub(days.saturating_mul(86400));
+        (days, cutoff)
+    });
+
+    let mut pruned = Vec::new();
+    for (key, entry) in entries {
+        let missing = |packages: &HashMap<String, Box<[Package]>>| {
+            checked.contains(&key.command.as_str())
+                && !packages.get(&key.command).is_some_and(|packages| {
+                    packages
+                        .iter()
+                        .any(|package| package.attribute == entry.derivation)
+                })
+        };
+        let action = if located.as_ref().is_some_and(missing) {
+            Some(Prune::Choice)
+        } else if let Some((days, _)) = unused_since
+            .filter(|(_, cutoff)| !entry.pinned && entry.used_at().unwrap_or_default() < *cutoff)
+        {
+            Some(Prune::Unused(days))
+        } else {
+            entry
+                .path
+                .clone()
+                .filter(|path| !Path::new(path).exists())
+                .map(Prune::Path)
+        };
+        if let Some(action) = action {
+            pruned.push((key.clone(), entry.clone(), action));
+        }
+    }
+
+    let verb = if dry_run { "would remove" } else { "removed" };
+    for (key, entry, action) in pruned {
+        let reason = match &action {
+            Prune::Path(path) => format!("path {path}, which no longer exists"),
+            Prune::Choice => format!(
+                "{}, which is no longer in the nix-index database",
+                entry.derivation
+            ),
+            Prune::Unused(days) => format!("entry, not used in {days} days"),
+        };
+        println!("{} ({}): {verb} {reason}", key.command, key.scope.source);
+
+        if dry_run {
+            continue;
+        }
+        match action {
+            Prune::Path(_) => cache.insert(key, entry.without_path()),
+            Prune::Choice | Prune::Unused(_) => cache.remove(key),
+        }
+    }
+}
+
+fn export(cache: &Cache, format: Format, strip_paths: bool) -> Result<String, Box<dyn Error>> {
+    let entries = cache
+        .entries()
+        .into_iter()
+        .map(|(key, entry)| ExportedEntry {
+            command: key.command.clone(),
+            scope: key.scope.clone(),
+            entry: if strip_paths {
+                entry.without_path()
+            } else {
+                entry.clone()
+            },
+        })
+        .collect();
+    let exported = Exported { entries };
+
+    Ok(match format {
+        Format::Json => serde_json::to_string_pretty(&exported)? + "\n",
+        Format::Toml => toml::to_string(&exported)?,
+    })
+}
+
+fn import(file: Option<&Path>, format: Option<Format>) -> Result<Exported, Box<dyn Error>> {
+    let (contents, format) = match file {
+        Some(file) if file != Path::new("-") => {
+            let format = format.unwrap_or_else(|| {
+                if file.extension().is_some_and(|ext| ext == "toml") {
+                    Format::Toml
+                } else {
+                    Format::Json
+                }
+            });
+            let contents = fs::read_to_string(file)
+                .map_err(|e| format!("failed to read '{}': {e}", file.display()))?;
+            (contents, format)
+        }
+        _ => {
+            let mut contents = String::new();
+            io::stdin().read_to_string(&mut contents)?;
+            (contents, format.unwrap_or(Format::Json))
+        }
+    };
+
+    Ok(match format {
+        Format::Json => serde_json::from_str(&contents)?,
+        Format::Toml => toml::from_str(&contents)?,
+    })
+}
+
+#[derive(Serialize)]
+struct EntryInfo<'a> {
+    command: &'a str,
+    source: String,
+    system: &'a str,
+    derivation: &'a str,
+    path: Option<&'a str>,
+    path_exists: bool,
+    pinned: bool,
+    resolved_at: Option<u64>,
+    revision: Option<&'a str>,
+    version: Option<&'a str>,
+    method: Option<Method>,
+    chosen_at: Option<u64>,
+    last_used: Option<u64>,
+    candidates: Option<&'a [String]>,
+    layer: Layer,
+}
+
+impl<'a> EntryInfo<'a> {
+    fn new(key: &'a CacheKey, entry: &'a CacheEntry, layer: Layer) -> Self {
+        Self {
+            command: &key.command,
+            source: key.scope.source.to_string(),
+            system: &key.scope.system,
+            derivation: &entry.derivation,
+            path: entry.path.as_deref(),
+            path_exists: entry.path.as_ref().is_some_and(|p| Path::new(p).exists()),
+            pinned: entry.pinned,
+            resolved_at: entry.resolved_at,
+            revision: entry.revision.as_deref(),
+            version: entry.version.as_deref(),
+            method: entry.method,
+            chosen_at: entry.chosen_at,
+            last_used: entry.last_used,
+            candidates: entry.candidates.as_ref().map(|c| c.attributes.as_slice()),
+            layer,
+        }
+    }
+
+    fn exists(&self) -> &'static str {
+        match (self.path, self.path_exists) {
+            (None, _) => "-",
+            (Some(_), true) => "yes",
+            (Some(_), false) => "no",
+        }
+    }
+}
+
+/// How long ago a Unix timestamp was, in its largest whole unit down to minutes
+fn format_age(timestamp: u64) -> String {
+    let secs = cache::now().saturating_sub(timestamp);
+    let Some((count, unit)) = [(86400, "day"), (3600, "hour"), (60, "minute")]
+        .into_iter()
+        .find(|(size, _)| secs >= *size)
+        .map(|(size, unit)| (secs / size, unit))
+    else {
+        return "just now".to_owned();
+    };
+    let plural = if count == 1 { "" } else { "s" };
+    format!("{count} {unit}{plural} ago")
+}
+
+fn format_time(timestamp: Option<u64>) -> String {
+    timestamp.map_or("-".to_owned(), format_age)
+}
+
+fn print_table(entries: &[EntryInfo]) {
+    let last_used: Vec<_> = entries.iter().map(|e| format_time(e.last_used)).collect();
+    let layers: Vec<_> = entries.iter().map(|e| e.layer.to_string()).collect();
+    let rows: Vec<[&str; 10]> = entries
+        .iter()
+        .zip(&last_used)
+        .zip(&layers)
+        .map(|((e, last_used), layer)| {
+            [
+                e.command,
+                &e.source,
+                e.system,
+                e.derivation,
+                e.version.unwrap_or("-"),
+                if e.pinned { "yes" } else { "no" },
+                layer,
+                e.exists(),
+                last_used,
+                e.path.unwrap_or("-"),
+            ]
+        })
+        .collect();
+
+    let header = [
+        "COMMAND",
+        "SOURCE",
+        "SYSTEM",
+        "DERIVATION",
+        "VERSION",
+        "PINNED",
+        "LAYER",
+        "EXISTS",
+        "LAST USED",
+        "PATH",
+    ];
+    print_rows(header, &rows);
+}
+
+/// Print `rows` under `header`, with the columns aligned
+fn print_rows<const N: usize>(header: [&str; N], rows: &[[&str; N]]) {
+    let mut widths = header.map(str::len);
+    for row in rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    for row in [header].iter().chain(rows) {
+        let line: Vec<_> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        println!("{}", line.join("  ").trim_end());
+    }
+}
+
+fn print_entry(entry: &EntryInfo) {
+    println!("command:     {}", entry.command);
+    println!("source:      {}", entry.source);
+    println!("system:      {}", entry.system);
+    println!("derivation:  {}", entry.derivation);
+    match entry.method {
+        Some(method) => println!("chosen:      {} ({method})", format_time(entry.chosen_at)),
+        None => println!("chosen:      {}", format_time(entry.chosen_at)),
+    }
+    println!("pinned:      {}", if entry.pinned { "yes" } else { "no" });
+    println!("layer:       {}", entry.layer);
+    println!("path:        {}", entry.path.unwrap_or("-"));
+    println!("path exists: {}", entry.exists());
+    println!("version:     {}", entry.version.unwrap_or("-"));
+    println!("resolved:    {}", format_time(entry.resolved_at));
+    println!("revision:    {}", entry.revision.unwrap_or("-"));
+    println!("last used:   {}", format_time(entry.last_used));
+    match entry.candidates {
+        Some(candidates) => println!("candidates:  {}", candidates.join(", ")),
+        None => println!("candidates:  -"),
+    }
+}
+
+fn print_json<T: Serialize + ?Sized>(value: &T) {
+    println!(
+        "{}",
+        serde_json::to_string_pretty(value).expect("cache entries serialize to JSON")
+    );
+}
+
+/// Run a `comma cache` subcommand. Commands given by name refer to entries in
+/// `scope`, listing and exporting covers all scopes.
+pub fn run(args: &CacheArgs, scope: Scope) -> ExitCode {
+    let mut cache = match Cache::new(scope) {
+        Ok(cache) => {
+            crate::print_warnings(&cache);
+            cache
+        }
+        Err(e) => {
+            eprintln!("failed to open cache: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    match &args.cmd {
+        CacheCmd::List => {
+            let user = cache.entries().into_iter().map(|e| (e, Layer::User));
+            let system = cache
+                .system_entries()
+                .into_iter()
+                .map(|e| (e, Layer::System));
+            let mut entries: Vec<_> = user
+                .chain(system)
+                .map(|((key, entry), layer)| EntryInfo::new(key, entry, layer))
+                .collect();
+            entries.sort_by_key(|e| e.command);
+            if args.json {
+                print_json(&entries);
+            } else {
+                print_table(&entries);
+            }
+        }
+        CacheCmd::Show { cmd } => {
+            let Some((entry, layer)) = cache.query_layer(cmd) else {
+                eprintln!("No cache entry for `{cmd}`.");
+                return ExitCode::FAILURE;
+            };
+            let key = CacheKey {
+                command: cmd.clone(),
+                scope: cache.scope().clone(),
+            };
+            let entry = EntryInfo::new(&key, &entry, layer);
+            if args.json {
+                print_json(&entry);
+            } else {
+                print_entry(&entry);
+            }
+        }
+        CacheCmd::Set { cmd, derivation } => {
+            let previous = cache.query(cmd);
+            cache.update(
+                cmd,
+                CacheEntry {
+                    pinned: previous.as_ref().is_some_and(|entry| entry.pinned),
+                    candidates: previous.and_then(|entry| entry.candidates),
+                    ..CacheEntry::chosen(derivation.clone(), Method::Manual)
+                },
+            );
+        }
+        CacheCmd::Pin { cmd } | CacheCmd::Unpin { cmd } => {
+            let Some(entry) = cache.query(cmd) else {
+                eprintln!("No cache entry for `{cmd}`, set one with `comma cache set {cmd} <derivation>`.");
+                return ExitCode::FAILURE;
+            };
+            let pinned = matches!(args.cmd, CacheCmd::Pin { .. });
+            cache.update(cmd, CacheEntry { pinned, ..entry });
+        }
+        CacheCmd::Rm { cmd } => match cache.query_layer(cmd) {
+            None => {
+                eprintln!("No cache entry for `{cmd}`.");
+                return ExitCode::FAILURE;
+            }
+            Some((_, Layer::System)) => {
+                eprintln!(
+                    "The entry for `{cmd}` comes from {SYSTEM_CACHE_FILE}, override it with `comma cache set {cmd} <derivation>`."
+                );
+                return ExitCode::FAILURE;
+            }
+            Some((_, Layer::User)) => cache.delete(cmd),
+        },
+        CacheCmd::Prune {
+            unused_days,
+            dry_run,
+        } => prune(&mut cache, *unused_days, *dry_run),
+        CacheCmd::Gc { days } => {
+            // Paths of commands pinned by projects have roots of their own
+            let project = match Cache::new_project(cache.scope().clone()) {
+                Ok(project) => project,
+                Err(e) => {
+                    eprintln!("failed to open project cache: {e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            crate::print_warnings(&project);
+            // Any number of days beyond the age of the cache keeps every root
+            let unused_for = Duration::from_secs(days.saturating_mul(86400));
+            for cache in [&cache, &project] {
+                match cache.drop_gc_roots(unused_for) {
+                    Ok(dropped) => {
+                        for root in &dropped {
+                            println!("removed {}", root.display());
+                        }
+                    }
+                    Err(e) => {
+                        eprintln!("failed to remove GC roots: {e}");
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+        }
+        CacheCmd::Stats { limit } => return stats(*limit, args.json),
+        CacheCmd::Export {
+            format,
+            strip_paths,
+            output,
+        } => {
+            let exported = match export(&cache, *format, *strip_paths) {
+                Ok(exported) => exported,
+                Err(e) => {
+                    eprintln!("failed to export cache: {e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            match output {
+                Some(output) => {
+                    if let Err(e) = fs::write(output, exported) {
+                        eprintln!("failed to write '{}': {e}", output.display());
+                        return ExitCode::FAILURE;
+                    }
+                }
+                None => print!("{exported}"),
+            }
+        }
+        CacheCmd::Import {
+            file,
+            format,
+            strategy,
+            strip_paths,
+        } => {
+            let imported = match import(file.as_deref(), *format) {
+                Ok(imported) => imported,
+                Err(e) => {
+                    eprintln!("failed to import cache: {e}");
+                    return ExitCode::FAILURE;
+                }
+            };
+            if *strategy == Strategy::Replace {
+                cache.clear();
+            }
+            let system = cache.scope().system.clone();
+            for ExportedEntry {
+                command,
+                mut scope,
+                entry,
+            } in imported.entries
+            {
+                // Store paths only exist on their own system, but the choice of
+                // package applies to any
+                let entry = if *strip_paths {
+                    scope.system.clone_from(&system);
+                    entry.without_path()
+                } else {
+                    entry
+                };
+                cache.insert(CacheKey { command, scope }, entry);
+            }
+        }
+    }
+
+    ExitCode::SUCCESS
+}
+
+#[derive(Serialize)]
+struct UsageInfo {
+    #[serde(flatten)]
+    usage: Usage,
+    frecency: u64,
+}
+
+/// Print the usage log, see `CacheCmd::Stats`
+fn stats(limit: Option<usize>, json: bool) -> ExitCode {
+    let usages = match UsageLog::new().and_then(|log| log.read()) {
+        Ok(usages) => usages,
+        Err(e) => {
+            eprintln!("failed to read usage log: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let now = cache::now();
+    let mut usages: Vec<_> = usages
+        .into_iter()
+        .map(|usage| UsageInfo {
+            frecency: usage.frecency(now),
+            usage,
+        })
+        .collect();
+    usages.sort_by(|a, b| {
+        (b.frecency, b.usage.count, &a.usage.command).cmp(&(
+            a.frecency,
+            a.usage.count,
+            &b.usage.command,
+        ))
+    });
+    if let Some(limit) = limit {
+        usages.truncate(limit);
+    }
+
+    if json {
+        print_json(&usages);
+        return ExitCode::SUCCESS;
+    }
+
+    let cells: Vec<_> = usages
+        .iter()
+        .map(|u| {
+            (
+                u.usage.count.to_string(),
+                format_age(u.usage.last_run),
+                u.frecency.to_string(),
+            )
+        })
+        .collect();
+    let rows: Vec<[&str; 5]> = usages
+        .iter()
+        .zip(&cells)
+        .map(|(u, (count, last_run, frecency))| {
+            [
+                u.usage.command.as_str(),
+                &u.usage.attribute,
+                count,
+                last_run,
+                frecency,
+            ]
+        })
+        .collect();
+    print_rows(
+        ["COMMAND", "PACKAGE", "RUNS", "LAST RUN", "FRECENCY"],
+        &rows,
+    );
+
+    ExitCode::SUCCESS
+}
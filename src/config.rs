@@ -0,0 +1,185 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    env,
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use log::{debug, error};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    cache::{Refresh, Ttl},
+    resolver::ResolverSpec,
+    select::Select,
+};
+
+type ResultDyn<T> = Result<T, Box<dyn Error>>;
+
+/// System-wide configuration file, overridden by the user's
+pub const SYSTEM_CONFIG_FILE: &str = "/etc/comma/config.toml";
+
+/// Name of the per-project file, looked up in the working directory and its
+/// parents
+pub const PROJECT_CONFIG_FILE: &str = ".comma.toml";
+
+/// Settings that can be set in a configuration file. Every setting is
+/// optional, unset ones fall through to the next file or the default.
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub picker: Option<String>,
+    pub nixpkgs_flake: Option<String>,
+    pub cache_level: Option<u8>,
+    pub ask: Option<bool>,
+    pub resolvers: Option<Vec<ResolverSpec>>,
+    pub path_ttl: Option<Ttl>,
+    pub refresh: Option<Refresh>,
+    pub gc_roots: Option<bool>,
+    pub frozen: Option<bool>,
+    pub select: Option<Select>,
+    /// Attribute to use for a command instead of asking the resolvers. Unlike
+    /// choices stored in the cache, these are kept when the cache is emptied.
+    #[serde(default)]
+    pub packages: HashMap<String, String>,
+    #[serde(default)]
+    pub aliases: HashMap<String, Alias>,
+}
+
+/// Another name for a command, run with extra arguments and environment
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Alias {
+    /// Command the alias runs, resolved like any other command
+    #[serde(alias = "package")]
+    pub command: String,
+    /// Arguments given before the ones passed to the alias
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub args: Vec<String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env: BTreeMap<String, String>,
+}
+
+/// A configuration file and its contents
+#[derive(Debug, Clone)]
+pub struct ConfigFile {
+    pub path: PathBuf,
+    pub config: Config,
+}
+
+impl ConfigFile {
+    /// Read and parse a configuration file, returning `None` if it does not
+    /// exist
+    pub fn read(path: &Path) -> ResultDyn<Option<Self>> {
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(format!("failed to read '{}': {e}", path.display()).into()),
+        };
+
+        debug!("reading configuration from {}", path.display());
+
+        let config = toml::from_str(&contents)
+            .map_err(|e| format!("failed to parse '{}': {e}", path.display()))?;
+
+        Ok(Some(Self {
+            path: path.to_owned(),
+            config,
+        }))
+    }
+}
+
+/// The package configured for `command` in the first file that has one,
+/// together with that file
+pub fn package_for<'a>(files: &'a [ConfigFile], command: &str) -> Option<(&'a str, &'a Path)> {
+    files.iter().find_map(|file| {
+        let derivation = file.config.packages.get(command)?;
+        Some((derivation.as_str(), file.path.as_path()))
+    })
+}
+
+/// The alias named `name` in the first file that defines it
+pub fn alias_for<'a>(files: &'a [ConfigFile], name: &str) -> Option<&'a Alias> {
+    files.iter().find_map(|file| file.config.aliases.get(name))
+}
+
+/// Settings a project can pin for everyone working in its directory tree
+#[derive(Deserialize, Default, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct ProjectConfig {
+    pub nixpkgs_flake: Option<String>,
+    /// Attribute to use for a command, taking priority over the cache and the
+    /// resolvers
+    #[serde(default)]
+    pub packages: HashMap<String, String>,
+}
+
+/// A project file and its contents
+#[derive(Debug, Clone)]
+pub struct ProjectFile {
+    pub path: PathBuf,
+    pub config: ProjectConfig,
+}
+
+impl ProjectFile {
+    /// Find the closest project file in `dir` or one of its parents
+    pub fn find_from(dir: &Path) -> ResultDyn<Option<Self>> {
+        let Some(path) = dir
+            .ancestors()
+            .map(|dir| dir.join(PROJECT_CONFIG_FILE))
+            .find(|path| path.is_file())
+        else {
+            return Ok(None);
+        };
+
+        debug!("reading project configuration from {}", path.display());
+
+        let contents = fs::read_to_string(&path)
+            .map_err(|e| format!("failed to read '{}': {e}", path.display()))?;
+        let config = toml::from_str(&contents)
+            .map_err(|e| format!("failed to parse '{}': {e}", path.display()))?;
+
+        Ok(Some(Self { path, config }))
+    }
+
+    /// Find the project file for the working directory
+    pub fn find() -> ResultDyn<Option<Self>> {
+        Self::find_from(&env::current_dir()?)
+    }
+
+    /// The settings of the project file that also exist in a configuration
+    /// file, so it can take part in the same precedence order
+    pub fn as_config_file(&self) -> ConfigFile {
+        ConfigFile {
+            path: self.path.clone(),
+            config: Config {
+                nixpkgs_flake: self.config.nixpkgs_flake.clone(),
+                packages: self.config.packages.clone(),
+                ..Config::default()
+            },
+        }
+    }
+}
+
+/// Location of the user's configuration file, `$XDG_CONFIG_HOME/comma/config.toml`
+pub fn user_config_file() -> Option<PathBuf> {
+    let base = xdg::BaseDirectories::with_prefix("comma").ok()?;
+    Some(base.get_config_home().join("config.toml"))
+}
+
+/// Load the existing configuration files, highest precedence first. Files
+/// that cannot be read are reported and skipped.
+pub fn load() -> Vec<ConfigFile> {
+    user_config_file()
+        .into_iter()
+        .chain([PathBuf::from(SYSTEM_CONFIG_FILE)])
+        .filter_map(|path| match ConfigFile::read(&path) {
+            Ok(file) => file,
+            Err(e) => {
+                error!("ignoring configuration file: {e}");
+                None
+            }
+        })
+        .collect()
+}
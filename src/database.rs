@@ -0,0 +1,380 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt,
+    fs::File,
+    io::{self, BufRead, BufReader, Read},
+    path::{Path, PathBuf},
+};
+
+use log::debug;
+use serde::Deserialize;
+
+/// Magic bytes at the start of every nix-index database
+const FILE_MAGIC: &[u8] = b"NIXI";
+
+/// The only database format version we know how to decode
+const FORMAT_VERSION: u64 = 1;
+
+/// Metadata marking the entry that closes the file list of a store path
+const STORE_PATH_META: &[u8] = b"p";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    /// The file is not a nix-index database, or uses a format version we do
+    /// not understand
+    UnsupportedFormat(String),
+    Corrupt(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(e) => write!(f, "failed to read nix-index database: {e}"),
+            Error::UnsupportedFormat(e) => write!(f, "unsupported nix-index database: {e}"),
+            Error::Corrupt(e) => write!(f, "corrupt nix-index database: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+#[derive(Deserialize)]
+struct StorePath {
+    store_dir: String,
+    hash: String,
+    name: String,
+    origin: PathOrigin,
+}
+
+impl StorePath {
+    /// The attribute and output, e.g. `hello.out`
+    fn attribute(&self) -> String {
+        format!("{}.{}", self.origin.attr, self.origin.output)
+    }
+
+    fn path(&self) -> PathBuf {
+        Path::new(&self.store_dir).join(format!("{}-{}", self.hash, self.name))
+    }
+}
+
+#[derive(Deserialize)]
+struct PathOrigin {
+    attr: String,
+    output: String,
+}
+
+/// Decoder for the frcode-like encoding used by nix-index.
+///
+/// Every entry is stored as `<metadata> \0 <shared prefix differential>
+/// <path suffix> \n`, where the differential is relative to the length of the
+/// prefix shared by the previous entry.
+struct Decoder<R> {
+    reader: R,
+    meta: Vec<u8>,
+    path: Vec<u8>,
+    shared_len: usize,
+}
+
+impl<R: BufRead> Decoder<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            meta: Vec::new(),
+            path: Vec::new(),
+            shared_len: 0,
+        }
+    }
+
+    /// Advance to the next entry, returning false at the end of the database
+    fn next_entry(&mut self) -> Result<bool, Error> {
+        self.meta.clear();
+        if self.reader.read_until(b'\0', &mut self.meta)? == 0 {
+            return Ok(false);
+        }
+        if self.meta.pop() != Some(b'\0') {
+            return Err(Error::Corrupt("truncated entry metadata".into()));
+        }
+
+        let shared_len = self.shared_len as isize + self.read_prefix_differential()?;
+        if shared_len < 0 || shared_len as usize > self.path.len() {
+            return Err(Error::Corrupt(format!(
+                "invalid shared prefix length {shared_len}"
+            )));
+        }
+        self.shared_len = shared_len as usize;
+
+        self.path.truncate(self.shared_len);
+        self.reader.read_until(b'\n', &mut self.path)?;
+        if self.path.pop() != Some(b'\n') {
+            return Err(Error::Corrupt("truncated entry path".into()));
+        }
+
+        Ok(true)
+    }
+
+    fn read_prefix_differential(&mut self) -> Result<isize, Error> {
+        let mut byte = [0; 1];
+        self.reader.read_exact(&mut byte)?;
+        if byte[0] != 0x80 {
+            return Ok(byte[0] as i8 as isize);
+        }
+
+        let mut bytes = [0; 2];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(i16::from_be_bytes(bytes) as isize)
+    }
+}
+
+/// Opens the database and checks its header, returning a decoder for the
+/// compressed entries that follow it
+fn open(database_file: &Path) -> Result<Decoder<impl BufRead>, Error> {
+    let mut file = BufReader::new(File::open(database_file)?);
+
+    let mut magic = [0; 4];
+    file.read_exact(&mut magic)?;
+    if magic != FILE_MAGIC {
+        return Err(Error::UnsupportedFormat("bad file magic".into()));
+    }
+
+    let mut version = [0; 8];
+    file.read_exact(&mut version)?;
+    let version = u64::from_le_bytes(version);
+    if version != FORMAT_VERSION {
+        return Err(Error::UnsupportedFormat(format!(
+            "format version {version}, expected {FORMAT_VERSION}"
+        )));
+    }
+
+    Ok(Decoder::new(BufReader::new(zstd::Decoder::with_buffer(
+        file,
+    )?)))
+}
+
+/// A package in the database
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Package {
+    /// The attribute and output, e.g. `hello.out`
+    pub attribute: String,
+    pub store_path: PathBuf,
+}
+
+/// Find all packages that ship `/bin/{command}` at the root of their output,
+/// the equivalent of `nix-locate --minimal --at-root --whole-name /bin/{command}`
+pub fn locate(database_file: &Path, command: &str) -> Result<Box<[Package]>, Error> {
+    Ok(locate_all(database_file, &[command])?
+        .remove(command)
+        .unwrap_or_default())
+}
+
+/// Like [`locate`] for several commands, in a single pass over the database
+pub fn locate_all(
+    database_file: &Path,
+    commands: &[&str],
+) -> Result<HashMap<String, Box<[Package]>>, Error> {
+    debug!(
+        "looking up {} command(s) in {}",
+        commands.len(),
+        database_file.display()
+    );
+
+    let mut decoder = open(database_file)?;
+    let wanted: HashMap<_, _> = commands
+        .iter()
+        .map(|command| (format!("/bin/{command}").into_bytes(), *command))
+        .collect();
+
+    let mut packages: HashMap<&str, Vec<Package>> = HashMap::new();
+    let mut seen = HashSet::new();
+    let mut found = Vec::new();
+
+    while decoder.next_entry()? {
+        if decoder.meta == STORE_PATH_META {
+            if !found.is_empty() {
+                let store_path: StorePath = serde_json::from_slice(&decoder.path)
+                    .map_err(|e| Error::Corrupt(format!("invalid store path: {e}")))?;
+                let package = Package {
+                    attribute: store_path.attribute(),
+                    store_path: store_path.path(),
+                };
+                for command in found.drain(..) {
+                    if seen.insert((command, package.attribute.clone())) {
+                        packages.entry(command).or_default().push(package.clone());
+                    }
+                }
+            }
+        } else if let Some(command) = wanted.get(&decoder.path) {
+            found.push(*command);
+        }
+    }
+
+    Ok(packages
+        .into_iter()
+        .map(|(command, packages)| (command.to_owned(), packages.into_boxed_slice()))
+        .collect())
+}
+
+/// Store paths of the given attributes, e.g. `hello.out`, as recorded in the
+/// database. Attributes the database does not know are left out.
+pub fn store_paths(
+    database_file: &Path,
+    attributes: &[String],
+) -> Result<HashMap<String, PathBuf>, Error> {
+    let mut decoder = open(database_file)?;
+    let mut paths = HashMap::new();
+
+    while decoder.next_entry()? {
+        if decoder.meta == STORE_PATH_META {
+            let store_path: StorePath = serde_json::from_slice(&decoder.path)
+                .map_err(|e| Error::Corrupt(format!("invalid store path: {e}")))?;
+            let attr = store_path.attribute();
+            if attributes.contains(&attr) {
+                paths.insert(attr, store_path.path());
+            }
+        }
+    }
+
+    Ok(paths)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Cursor};
+
+    use super::*;
+
+    /// Encode entries the way nix-index does, see [`Decoder`]
+    fn encode(entries: &[(&[u8], &[u8])]) -> Vec<u8> {
+        let mut out = Vec::new();
+        let mut last: &[u8] = &[];
+        let mut last_shared = 0;
+        for (meta, path) in entries {
+            let shared = last.iter().zip(*path).take_while(|(a, b)| a == b).count();
+            let differential = shared as isize - last_shared as isize;
+            out.extend(*meta);
+            out.push(b'\0');
+            if (-127..=127).contains(&differential) {
+                out.push(differential as i8 as u8);
+            } else {
+                out.push(0x80);
+                out.extend((differential as i16).to_be_bytes());
+            }
+            out.extend(&path[shared..]);
+            out.push(b'\n');
+            last = path;
+            last_shared = shared;
+        }
+        out
+    }
+
+    fn store_path(attr: &str, output: &str) -> Vec<u8> {
+        format!(
+            r#"{{"store_dir":"/nix/store","hash":"h{attr}","name":"{attr}-1.0","origin":{{"attr":"{attr}","output":"{output}","toplevel":true,"system":null}}}}"#
+        )
+        .into_bytes()
+    }
+
+    /// A database where `hello.out` and a package with a long name ship
+    /// `/bin/hello`, and `python3.out` ships `/bin/python3`
+    fn database() -> Vec<u8> {
+        let long = "x".repeat(200);
+        let long_path = format!("/bin/{}", "y".repeat(300));
+        let entries: Vec<(&[u8], Vec<u8>)> = vec![
+            (b"d", b"/bin".to_vec()),
+            (b"x", b"/bin/hello".to_vec()),
+            (b"p", store_path("hello", "out")),
+            (b"x", long_path.clone().into_bytes()),
+            (b"x", format!("{long_path}z").into_bytes()),
+            (b"x", b"/bin/hello".to_vec()),
+            (b"p", store_path(&long, "bin")),
+            (b"x", b"/bin/python3".to_vec()),
+            (b"p", store_path("python3", "out")),
+        ];
+        let entries: Vec<_> = entries.iter().map(|(m, p)| (*m, p.as_slice())).collect();
+
+        let mut file = FILE_MAGIC.to_vec();
+        file.extend(FORMAT_VERSION.to_le_bytes());
+        file.extend(zstd::encode_all(encode(&entries).as_slice(), 0).unwrap());
+        file
+    }
+
+    fn write_database(name: &str, contents: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("comma-{}-{name}", std::process::id()));
+        fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn decodes_prefix_differentials() {
+        // Sharing 300 more bytes than the previous entry, then 300 fewer,
+        // needs the 0x80 escape and a 16-bit differential
+        let long = format!("/bin/{}", "y".repeat(300));
+        let longer = format!("{long}z");
+        let entries: [(&[u8], &[u8]); 5] = [
+            (b"a", b"/bin/hello"),
+            (b"b", long.as_bytes()),
+            (b"c", longer.as_bytes()),
+            (b"d", b"/share"),
+            (b"e", b"/share/man"),
+        ];
+        let encoded = encode(&entries);
+        assert_eq!(encoded.iter().filter(|&&b| b == 0x80).count(), 2);
+        let mut decoder = Decoder::new(Cursor::new(encoded));
+
+        for (meta, path) in entries {
+            assert!(decoder.next_entry().unwrap());
+            assert_eq!(decoder.meta, meta);
+            assert_eq!(decoder.path, path);
+        }
+        assert!(!decoder.next_entry().unwrap());
+    }
+
+    #[test]
+    fn locates_commands() {
+        let path = write_database("locate", &database());
+
+        let found = locate_all(&path, &["hello", "python3", "gcc"]).unwrap();
+        let attributes = |command| {
+            found[command]
+                .iter()
+                .map(|package| package.attribute.clone())
+                .collect::<Vec<_>>()
+        };
+        let long = format!("{}.bin", "x".repeat(200));
+        assert_eq!(attributes("hello"), ["hello.out".to_owned(), long]);
+        assert_eq!(
+            *found["python3"],
+            [Package {
+                attribute: "python3.out".into(),
+                store_path: "/nix/store/hpython3-python3-1.0".into(),
+            }]
+        );
+        assert!(!found.contains_key("gcc"));
+
+        let paths = store_paths(&path, &["python3.out".to_owned()]).unwrap();
+        assert_eq!(
+            paths["python3.out"],
+            Path::new("/nix/store/hpython3-python3-1.0")
+        );
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn rejects_unknown_versions() {
+        let mut contents = database();
+        contents[4] = 2;
+        let path = write_database("version", &contents);
+
+        let result = locate(&path, "hello");
+        assert!(matches!(result, Err(Error::UnsupportedFormat(_))));
+
+        fs::remove_file(path).unwrap();
+    }
+}
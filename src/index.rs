@@ -1,11 +1,11 @@
 use std::{
     env,
     path::{Path, PathBuf},
-    time::{Duration, SystemTime},
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 /// Prints a warning if the nix-index database is non-existent
-pub fn check_database_exists() -> Result<(), ()> {
+fn check_database_exists() -> Result<(), ()> {
     let database_file = get_database_file();
     if !database_file.exists() {
         eprintln!("Warning: Nix-index database does not exist, either obtain a prebuilt database from https://github.com/nix-community/nix-index-database or try updating with `nix run 'nixpkgs#nix-index' --extra-experimental-features 'nix-command flakes'`.");
@@ -32,7 +32,7 @@ pub fn check_database_updated() {
 }
 
 /// Get the location of the nix-index database file
-fn get_database_file() -> PathBuf {
+pub fn get_database_file() -> PathBuf {
     match env::var("NIX_INDEX_DATABASE") {
         Ok(db) => {
             let path = PathBuf::from(db);
@@ -50,6 +50,24 @@ fn get_database_file() -> PathBuf {
     }
 }
 
+/// Identifies the current nix-index database by its resolved path and
+/// modification time, which both change when it is updated
+pub fn database_stamp() -> Option<String> {
+    let database_file = get_database_file().canonicalize().ok()?;
+    let modified = database_file
+        .metadata()
+        .ok()?
+        .modified()
+        .ok()?
+        .duration_since(UNIX_EPOCH)
+        .ok()?;
+    Some(format!(
+        "{}@{}",
+        database_file.display(),
+        modified.as_nanos()
+    ))
+}
+
 /// Test whether the database is more than 30 days old
 fn is_database_old(database_file: &Path) -> bool {
     let Ok(metadata) = database_file.metadata() else {
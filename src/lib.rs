@@ -0,0 +1,161 @@
+//! Finds the nixpkgs packages providing an executable and where that
+//! executable is in the nix store, as used by the `,` command.
+//!
+//! ```no_run
+//! use comma::{cache::Scope, nix::Source, resolver::Database, Cache};
+//!
+//! let source = Source::Flake("nixpkgs".into());
+//! let candidates = comma::candidates(&Database, "jq")?;
+//! let mut cache = Cache::new(Scope::new(source.clone())).ok();
+//! let resolution = comma::resolve(cache.as_mut(), &source, "jq", &candidates[0])?;
+//! println!("{} provides {}", resolution.candidate, resolution.path);
+//! # Ok::<(), Box<dyn std::error::Error>>(())
+//! ```
+
+pub mod cache;
+pub mod config;
+pub mod database;
+pub mod index;
+pub mod nix;
+pub mod rank;
+pub mod resolver;
+pub mod select;
+pub mod usage;
+
+use std::{error::Error, fmt, fs, path::Path};
+
+use log::{debug, error};
+
+pub use cache::{Cache, CacheEntry};
+use nix::Source;
+use resolver::Resolver;
+
+type ResultDyn<T> = Result<T, Box<dyn Error>>;
+
+/// A package providing an executable, identified by its attribute and output,
+/// e.g. `python3.out`
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Candidate {
+    pub attribute: String,
+    /// Store path of the package, if the resolver knows it
+    pub store_path: Option<String>,
+}
+
+impl Candidate {
+    pub fn new(attribute: impl Into<String>) -> Self {
+        Self {
+            attribute: attribute.into(),
+            store_path: None,
+        }
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.attribute)
+    }
+}
+
+/// An executable located in the nix store
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Resolution {
+    pub command: String,
+    pub candidate: Candidate,
+    /// Absolute path to the executable
+    pub path: String,
+}
+
+/// Find the packages providing `command`
+pub fn candidates(resolver: &dyn Resolver, command: &str) -> ResultDyn<Vec<Candidate>> {
+    resolver.resolve(command)
+}
+
+/// Find where `command` from `candidate` is in the nix store, building the
+/// package if necessary. With a cache, a path cached for the same choice is
+/// reused while it still exists, and newly built paths are recorded.
+pub fn resolve(
+    cache: Option<&mut Cache>,
+    source: &Source,
+    command: &str,
+    candidate: &Candidate,
+) -> ResultDyn<Resolution> {
+    // Only reuse and record paths when the cache agrees with the candidate,
+    // resolving another package must not change the user's choice
+    let cache = cache.filter(|cache| cache.scope().source == *source);
+    let path = match cache {
+        Some(cache) => match cache.query(command) {
+            Some(entry) if entry.derivation == candidate.attribute => {
+                command_path(Some(cache), source, command, &entry)?
+            }
+            _ => nix::build_command_path(source, &candidate.attribute, command, None)?,
+        },
+        None => nix::build_command_path(source, &candidate.attribute, command, None)?,
+    };
+
+    Ok(Resolution {
+        command: command.to_owned(),
+        candidate: candidate.clone(),
+        path,
+    })
+}
+
+/// Path to `command` from a cache entry. If the entry has a path that was not
+/// garbage collected it is used directly, otherwise the package is built and
+/// the new path is recorded in the cache, if the cache is scoped to `source`,
+/// along with when it was resolved and the revision of nixpkgs. Pass
+/// [`CacheEntry::without_path`] to resolve an expired path again. If the cache
+/// has GC roots enabled, the path is protected from garbage collection.
+pub fn command_path(
+    cache: Option<&mut Cache>,
+    source: &Source,
+    command: &str,
+    entry: &CacheEntry,
+) -> ResultDyn<String> {
+    let cache = cache.filter(|cache| cache.scope().source == *source);
+    let gc_root = cache.as_deref().and_then(|cache| cache.gc_root(command));
+
+    match &entry.path {
+        // If we have the path in the cache and it is not garbage collected
+        // (so the path still exists), it should be safe to use it directly
+        Some(path) if Path::new(&path).exists() => {
+            debug!("found path from cache for command '{command}': {path}");
+
+            // Paths cached before GC roots were enabled get one now
+            let rooted = |gc_root: &Path| {
+                fs::read_link(gc_root).is_ok_and(|target| Path::new(path).starts_with(target))
+            };
+            if let Some(gc_root) = gc_root.filter(|gc_root| !rooted(gc_root)) {
+                if let Err(e) = nix::add_gc_root(path, &gc_root) {
+                    error!("{e}");
+                }
+            }
+
+            Ok(path.to_owned())
+        }
+        // Otherwise, we need to find the command path
+        _ => {
+            // The revision is only recorded along with the path, and must be
+            // the one the path is built from
+            let (locked, revision) = match cache {
+                Some(_) => nix::lock(source),
+                None => (source.clone(), None),
+            };
+            let path =
+                nix::build_command_path(&locked, &entry.derivation, command, gc_root.as_deref())?;
+            debug!("found path from nix for command '{command}': {path}");
+
+            if let Some(cache) = cache {
+                let entry = CacheEntry {
+                    path: Some(path.clone()),
+                    resolved_at: Some(cache::now()),
+                    revision,
+                    version: nix::version_from_path(&path),
+                    ..entry.clone()
+                };
+                cache.update(command, entry);
+            }
+
+            Ok(path)
+        }
+    }
+}
@@ -1,26 +1,97 @@
-mod cache;
-mod index;
+mod cache_cmd;
+mod picker;
+mod settings;
 mod shell;
 
 use std::{
     env,
-    io::{self, Write},
+    error::Error,
+    io::{self, IsTerminal, Write},
     os::unix::prelude::CommandExt,
     path::Path,
     process::{self, Command, ExitCode, Stdio},
 };
 
-use cache::{Cache, CacheEntry};
-use clap::{crate_version, Args, CommandFactory, Parser, Subcommand, ValueHint};
+use clap::{crate_version, Args, CommandFactory, FromArgMatches, Parser, Subcommand, ValueHint};
 use clap_complete::{generate, Generator, Shell};
+use comma::{
+    cache::{Candidates, Method, Refresh, Scope, Ttl},
+    config::{self, Alias},
+    database, index,
+    nix::{self, Source},
+    rank,
+    resolver::{self, Resolver, ResolverSpec},
+    select::Select,
+    usage::UsageLog,
+    Cache, CacheEntry,
+};
 use log::{debug, error, trace};
 
-fn pick(picker: &str, derivations: &[String]) -> Option<String> {
-    let mut picker_process = Command::new(picker)
+/// Exit status when a command was never resolved, and `--frozen` or the
+/// selection strategy forbids resolving it
+const EXIT_NOT_RESOLVED: u8 = 3;
+
+/// Picker name selecting the picker built into comma
+const BUILTIN_PICKER: &str = "builtin";
+
+/// How the package for a command is chosen when nothing was chosen before
+struct Selection<'a> {
+    /// Strategy to use, other than `Auto`
+    select: Select,
+    picker: &'a str,
+    /// Where the candidates are described from for previews
+    source: &'a Source,
+}
+
+fn pick_builtin(source: &Source, derivations: &[String]) -> Option<String> {
+    let source = source.clone();
+    let preview: picker::Preview = Box::new(move |attribute| {
+        describe(&source, attribute).unwrap_or_else(|e| vec![e.to_string()])
+    });
+    picker::pick(derivations, Some(preview))
+        .inspect_err(|err| eprintln!("Error: failed to run the builtin picker: {err}"))
+        .ok()
+        .flatten()
+}
+
+/// Quote `s` for a POSIX shell
+fn shell_quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', r"'\''"))
+}
+
+fn pick(picker: &str, source: &Source, derivations: &[String]) -> Option<String> {
+    if picker == BUILTIN_PICKER {
+        return pick_builtin(source, derivations);
+    }
+
+    let mut picker_cmd = Command::new(picker);
+
+    // fzf can show what comma knows about the highlighted package
+    if Path::new(picker)
+        .file_name()
+        .is_some_and(|name| name == "fzf")
+    {
+        if let Ok(exe) = env::current_exe() {
+            let mut preview = format!("{} --describe {{}}", shell_quote(&exe.to_string_lossy()));
+            if let Source::Flake(flake) = source {
+                preview.push_str(&format!(" --nixpkgs-flake {}", shell_quote(flake)));
+            }
+            picker_cmd.args(["--preview", &preview]);
+        }
+    }
+
+    let mut picker_process = match picker_cmd
         .stdin(Stdio::piped())
         .stdout(Stdio::piped())
         .spawn()
-        .unwrap_or_else(|err| panic!("failed to execute {picker}: {err}"));
+    {
+        Ok(process) => process,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            debug!("picker {picker} not found, using the builtin picker");
+            return pick_builtin(source, derivations);
+        }
+        Err(err) => panic!("failed to execute {picker}: {err}"),
+    };
 
     let picker_stdin = picker_process.stdin.as_mut().unwrap();
 
@@ -41,175 +112,303 @@ fn pick(picker: &str, derivations: &[String]) -> Option<String> {
     )
 }
 
-fn index_database(command: &str) -> Option<Box<[String]>> {
-    index::check_database_updated();
+/// Size in bytes with a binary unit, e.g. `45.3 MiB`
+fn format_size(bytes: u64) -> String {
+    let mut size = bytes as f64;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if size < 1024.0 {
+            return if unit == "B" {
+                format!("{bytes} B")
+            } else {
+                format!("{size:.1} {unit}")
+            };
+        }
+        size /= 1024.0;
+    }
+    format!("{size:.1} TiB")
+}
+
+/// Describe the package `attribute` in a few lines: its version, description,
+/// licenses, whether it is in the local store and the size of its closure
+fn describe(source: &Source, attribute: &str) -> Result<Vec<String>, Box<dyn Error>> {
+    let description = nix::describe(source, attribute)?;
+
+    // Packages that cannot be evaluated may still be known to nix-index
+    let store_path = description.out_path.clone().or_else(|| {
+        let attributes = [attribute.to_owned()];
+        database::store_paths(&index::get_database_file(), &attributes)
+            .ok()?
+            .remove(attribute)
+            .map(|path| path.to_string_lossy().into_owned())
+    });
+    let in_store = store_path.as_ref().filter(|path| Path::new(path).exists());
+    let closure_size = in_store.and_then(|path| nix::closure_size(path));
+
+    let licenses = if description.licenses.is_empty() {
+        "-".to_owned()
+    } else {
+        description.licenses.join(", ")
+    };
+    Ok(vec![
+        format!(
+            "version:     {}",
+            description.version.as_deref().unwrap_or("-")
+        ),
+        format!(
+            "description: {}",
+            description.description.as_deref().unwrap_or("-")
+        ),
+        format!("license:     {licenses}"),
+        format!(
+            "in store:    {}",
+            if in_store.is_some() { "yes" } else { "no" }
+        ),
+        format!(
+            "closure:     {}",
+            closure_size.map_or("-".to_owned(), format_size)
+        ),
+    ])
+}
 
-    let nix_locate_output = Command::new("nix-locate")
-        .args(["--minimal", "--at-root", "--whole-name"])
-        .arg(format!("/bin/{command}"))
-        .output()
-        .expect("failed to execute nix-locate");
+/// Find the packages providing `command`, reusing the candidates of the
+/// `previous` entry while they come from the same resolvers and database.
+/// Commands that were not found are remembered in the cache until the
+/// database changes, and with `use_misses` they are not looked up again until
+/// then. Neither is done when a resolver's answers can change with the
+/// database unchanged, e.g. with a mapping file.
+fn index_database(
+    resolver: &dyn Resolver,
+    cache: &mut Option<Cache>,
+    command: &str,
+    use_misses: bool,
+    previous: Option<&CacheEntry>,
+) -> Option<Candidates> {
+    let resolvers = resolver.name();
+    let database = index::database_stamp().filter(|_| resolver.uses_database());
+
+    if let Some(candidates) = previous
+        .and_then(|entry| entry.candidates.as_ref())
+        .filter(|candidates| candidates.is_current(&resolvers, database.as_deref()))
+    {
+        debug!("using cached candidates for command '{command}'");
+        return Some(candidates.clone());
+    }
 
-    if !nix_locate_output.status.success() {
-        match std::str::from_utf8(&nix_locate_output.stderr) {
-            Ok(stderr) => eprintln!("nix-locate failed with: {stderr}"),
-            Err(_) => eprintln!("nix-locate failed"),
+    if let (Some(cache), Some(database)) = (cache.as_ref(), &database) {
+        if use_misses && cache.is_miss(command, &resolvers, database) {
+            debug!("'{command}' was not found in this database before");
+            eprintln!("No executable `{command}` found in nix-index database.");
+            return None;
         }
-        return None;
     }
 
-    let attrs = nix_locate_output.stdout;
+    let attrs = match comma::candidates(resolver, command) {
+        Ok(attrs) => attrs,
+        Err(e) => {
+            eprintln!("{e}");
+            return None;
+        }
+    };
 
     if attrs.is_empty() {
+        if let (Some(cache), Some(database)) = (cache.as_mut(), &database) {
+            cache.record_miss(command, &resolvers, database);
+        }
         eprintln!("No executable `{command}` found in nix-index database.");
         return None;
     }
+    if let Some(cache) = cache {
+        cache.forget_miss(command, &resolvers);
+    }
 
-    Some(
-        std::str::from_utf8(&attrs)
-            .expect("fail")
-            .trim()
-            .split('\n')
-            .map(|s| s.to_owned())
+    Some(Candidates {
+        store_paths: attrs
+            .iter()
+            .filter_map(|c| Some((c.attribute.clone(), c.store_path.clone()?)))
             .collect(),
-    )
+        attributes: attrs.into_iter().map(|c| c.attribute).collect(),
+        resolvers,
+        database,
+    })
 }
 
-fn index_database_pick(command: &str, picker: &str) -> Option<String> {
-    let attrs = index_database(command)?;
+/// Report the problems met while opening `cache`
+fn print_warnings(cache: &Cache) {
+    for warning in cache.warnings() {
+        eprintln!("Warning: {warning}");
+    }
+}
+
+/// Record that `command` runs from `attribute` in the usage log
+fn record_usage(command: &str, attribute: &str) {
+    if let Err(e) = UsageLog::new().and_then(|log| log.record(command, attribute)) {
+        error!("failed to record usage of '{command}': {e}");
+    }
+}
 
+/// The candidates for `command`, best first
+fn ranked(command: &str, candidates: &Candidates, previous: Option<&CacheEntry>) -> Vec<String> {
+    let mut attrs = candidates.attributes.clone();
     if attrs.len() > 1 {
-        pick(picker, &attrs)
-    } else {
-        attrs.first().map(|s| s.trim().to_owned())
+        let in_store = rank::in_store(&candidates.store_paths);
+        let frecency = UsageLog::new()
+            .and_then(|log| log.frecency())
+            .inspect_err(|e| debug!("failed to read the usage log: {e}"))
+            .unwrap_or_default();
+        let previous = previous.map(|p| p.derivation.as_str());
+        rank::rank(command, &mut attrs, previous, &frecency, &in_store);
     }
+    attrs
 }
 
-fn run_command_or_open_shell(
-    use_channel: bool,
-    choice: &str,
+/// Choose the package for `command` among its candidates as `selection`
+/// says. Fails with the exit status to use.
+fn index_database_pick(
+    resolver: &dyn Resolver,
+    cache: &mut Option<Cache>,
     command: &str,
-    trail: &[String],
-    nixpkgs_flake: &str,
-) -> Command {
-    let mut run_cmd = Command::new("nix");
-
-    run_cmd.args([
-        "--extra-experimental-features",
-        "nix-command flakes",
-        "shell",
-    ]);
-
-    if use_channel {
-        run_cmd.args(["-f", "<nixpkgs>", choice]);
-    } else {
-        run_cmd.args([format!("{nixpkgs_flake}#{choice}")]);
-    }
+    selection: &Selection,
+    use_misses: bool,
+    previous: Option<&CacheEntry>,
+) -> Result<CacheEntry, ExitCode> {
+    let candidates =
+        index_database(resolver, cache, command, use_misses, previous).ok_or(ExitCode::FAILURE)?;
+    // The picker starts on the best ranked candidate
+    let attrs = ranked(command, &candidates, previous);
+
+    let refuse = |reason: String| {
+        eprintln!(
+            "{reason}\nPackages that contain /bin/{command}:\n{}",
+            attrs
+                .iter()
+                .map(|a| format!("- {a}"))
+                .collect::<Box<[String]>>()
+                .join("\n")
+        );
+        ExitCode::from(EXIT_NOT_RESOLVED)
+    };
 
-    if !command.is_empty() {
-        run_cmd.args(["--command", command]);
-        if !trail.is_empty() {
-            run_cmd.args(trail);
+    let (derivation, method) = match selection.select {
+        Select::CachedOnly => {
+            return Err(refuse(format!(
+                "`{command}` was never resolved, and --select cached-only forbids resolving it."
+            )))
+        }
+        _ if attrs.len() == 1 => (attrs[0].trim().to_owned(), Method::Single),
+        Select::Auto | Select::Pick => (
+            pick(selection.picker, selection.source, &attrs).ok_or(ExitCode::FAILURE)?,
+            Method::Picker,
+        ),
+        Select::First => (candidates.attributes[0].clone(), Method::First),
+        Select::Heuristic => {
+            // Unlike the picker's order, the same on every machine
+            let mut attrs = candidates.attributes.clone();
+            rank::rank_by_attribute(command, &mut attrs);
+            (attrs[0].clone(), Method::Heuristic)
+        }
+        Select::Fail => {
+            return Err(refuse(format!(
+                "Several packages contain `{command}`, and --select fail forbids choosing one. \
+                 Choose it with --select or in the [packages] table."
+            )))
         }
     };
 
-    trace!("run nix command arguments: {run_cmd:?}");
-
-    run_cmd
+    Ok(CacheEntry {
+        candidates: Some(candidates),
+        ..CacheEntry::chosen(derivation, method)
+    })
 }
 
-fn get_command_path(use_channel: bool, choice: &str, command: &str, nixpkgs_flake: &str) -> String {
-    let mut run_cmd = Command::new("nix");
-
-    run_cmd.args([
-        "--extra-experimental-features",
-        "nix-command flakes",
-        "build",
-        "--print-out-paths",
-        "--no-link",
-    ]);
-
-    if use_channel {
-        run_cmd.args(["-f", "<nixpkgs>", choice]);
-    } else {
-        run_cmd.args([format!("{nixpkgs_flake}#{choice}")]);
-    }
-
-    let result = run_cmd
-        .stdout(Stdio::piped())
-        .spawn()
-        .unwrap_or_else(|err| panic!("failed to execute nix: {err}"));
-
-    // It is safe to assume that only one path will be printed because
-    // nix-locate appends the output to the derivation name
-    // (e.g., firefox.out instead of firefox)
-    let output = result.wait_with_output().unwrap().stdout;
-    let base_path = std::str::from_utf8(&output)
-        .unwrap_or_else(|err| panic!("nix outputted invalid UTF-8: {err}"))
-        .trim();
-
-    // It is safe to assume that command is in $out/bin/{command} from
-    // the derivation, since this was already filtered by nix-locate
-    format!("{base_path}/bin/{command}")
-}
-
-fn get_command_path_from_cache(
+fn command_path(
     cache: &mut Option<Cache>,
-    entry: &CacheEntry,
-    use_channel: bool,
+    source: &Source,
     command: &str,
-    nixpkgs_flake: &str,
-) -> String {
-    match &entry.path {
-        // If we have the path in the cache and it is not garbage collected
-        // (so the path still exists), it should be safe to use it directly
-        Some(path) if Path::new(&path).exists() => {
-            debug!("found path from cache for command '{command}': {path}");
-            path.to_owned()
+    entry: &CacheEntry,
+) -> Option<String> {
+    match comma::command_path(cache.as_mut(), source, command, entry) {
+        Ok(path) => Some(path),
+        Err(e) => {
+            eprintln!("{e}");
+            None
         }
-        // Otherwise, we need to find the command path
-        _ => match cache {
-            Some(ref mut cache) => {
-                let path = get_command_path(use_channel, &entry.derivation, command, nixpkgs_flake);
-                debug!("found path from nix for command '{command}': {path}");
-
-                let entry = CacheEntry {
-                    path: Some(path.clone()),
-                    ..entry.clone()
-                };
-                cache.update(command, entry);
-
-                path
-            }
-
-            None => {
-                let path = get_command_path(use_channel, &entry.derivation, command, nixpkgs_flake);
-                debug!("found path from nix for command '{command}': {path}");
-
-                path
-            }
-        },
     }
 }
 
 fn run_command_from_cache(
     cache: &mut Option<Cache>,
-    entry: &CacheEntry,
-    use_channel: bool,
+    source: &Source,
     command: &str,
     trail: &[String],
-    nixpkgs_flake: &str,
-) -> Command {
-    let path = get_command_path_from_cache(cache, entry, use_channel, command, nixpkgs_flake);
+    entry: &CacheEntry,
+    alias: Option<&Alias>,
+) -> Option<Command> {
+    let path = command_path(cache, source, command, entry)?;
 
     let mut run_cmd = Command::new(path);
+    if let Some(alias) = alias {
+        run_cmd.args(&alias.args).envs(&alias.env);
+    }
     if !trail.is_empty() {
         run_cmd.args(trail);
     }
 
     trace!("run command from cache arguments: {run_cmd:?}");
 
-    run_cmd
+    Some(run_cmd)
+}
+
+/// Resolve the path of the cached entry for `command` again, see
+/// [`spawn_refresh`]
+fn refresh_path(cache: Option<Cache>, source: &Source, command: &str) -> ExitCode {
+    let Some(mut cache) = cache else {
+        return ExitCode::FAILURE;
+    };
+    let Some(entry) = cache.query(command) else {
+        debug!("no cache entry to refresh for command '{command}'");
+        return ExitCode::FAILURE;
+    };
+
+    match comma::command_path(Some(&mut cache), source, command, &entry.without_path()) {
+        Ok(_) => ExitCode::SUCCESS,
+        Err(e) => {
+            error!("failed to refresh path for command '{command}': {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Start comma again in the background to resolve the expired path of
+/// `command`, so that the next run uses the new one
+fn spawn_refresh(source: &Source, command: &str, gc_roots: bool) {
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(e) => {
+            debug!("cannot refresh path in the background: {e}");
+            return;
+        }
+    };
+
+    let mut refresh_cmd = Command::new(exe);
+    if let Source::Flake(flake) = source {
+        refresh_cmd.args(["--nixpkgs-flake", flake]);
+    }
+    if gc_roots {
+        refresh_cmd.arg("--gc-roots");
+    }
+    refresh_cmd
+        .args(["--refresh-path", "--", command])
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        // Keep it running if the command is interrupted
+        .process_group(0);
+
+    trace!("refresh command arguments: {refresh_cmd:?}");
+
+    if let Err(e) = refresh_cmd.spawn() {
+        debug!("failed to refresh path in the background: {e}");
+    }
 }
 
 fn confirmer(run_cmd: &Command) -> bool {
@@ -233,7 +432,18 @@ fn confirmer(run_cmd: &Command) -> bool {
 fn main() -> ExitCode {
     env_logger::init();
 
-    let args = Opt::parse();
+    let matches = Opt::command().get_matches();
+    let mut args = Opt::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    let project = config::ProjectFile::find().unwrap_or_else(|e| {
+        error!("ignoring project file: {e}");
+        None
+    });
+    let config_files: Vec<_> = project
+        .iter()
+        .map(config::ProjectFile::as_config_file)
+        .chain(config::load())
+        .collect();
+    let settings = settings::merge(&mut args, &matches, &config_files, project.as_ref());
 
     if args.mangen {
         use clap::CommandFactory;
@@ -253,18 +463,64 @@ fn main() -> ExitCode {
         return ExitCode::SUCCESS;
     }
 
+    if args.show_config {
+        settings::show(&settings, &config_files);
+        return ExitCode::SUCCESS;
+    }
+
+    // A flake pinned by the project is used even if NIX_PATH has a channel
+    let project_flake = project
+        .as_ref()
+        .and_then(|project| project.config.nixpkgs_flake.as_ref());
+    let source = if project_flake == Some(&args.nixpkgs_flake) {
+        Source::Flake(args.nixpkgs_flake.clone())
+    } else {
+        Source::from_env(&args.nixpkgs_flake)
+    };
+
+    if let Some(SubCmds::Cache(ref cache_args)) = args.subcmds {
+        return cache_cmd::run(cache_args, Scope::new(source));
+    }
+
+    if let Some(ref attribute) = args.describe {
+        return match describe(&source, attribute) {
+            Ok(lines) => {
+                println!("{}", lines.join("\n"));
+                ExitCode::SUCCESS
+            }
+            Err(e) => {
+                eprintln!("{e}");
+                ExitCode::FAILURE
+            }
+        };
+    }
+
     let mut cache = if args.cache_level == 0 {
         None
     } else {
-        match Cache::new() {
+        let scope = Scope::new(source.clone());
+        let cache = if args.frozen {
+            Cache::new_read_only(scope)
+        } else {
+            Cache::new(scope)
+        };
+        match cache {
             Err(e) => {
                 error!("failed to initialize cache, disabling related functionality: {e}");
                 None
             }
-            Ok(x) => Some(x),
+            Ok(mut x) => {
+                print_warnings(&x);
+                x.set_gc_roots(args.gc_roots);
+                Some(x)
+            }
         }
     };
 
+    if args.refresh_path {
+        return refresh_path(cache, &source, &args.cmd[0]);
+    }
+
     if args.empty_cache {
         if let Some(ref mut cache) = cache {
             cache.empty();
@@ -279,70 +535,188 @@ fn main() -> ExitCode {
         };
     }
 
-    let (command, trail) = if let Some(SubCmds::Man(ManArgs { ref cmd })) = args.subcmds {
+    let (name, trail) = if let Some(SubCmds::Man(ManArgs { ref cmd })) = args.subcmds {
         (&cmd[0], &cmd[1..])
     } else {
         (&args.cmd[0], &args.cmd[1..])
     };
 
+    let alias = config::alias_for(&config_files, name);
+    let command = match alias {
+        Some(alias) => {
+            debug!("'{name}' is an alias for {alias:?}");
+            &alias.command
+        }
+        None => name,
+    };
+
+    // The candidates of the entry are reused when choosing again
+    let previous = cache.as_ref().and_then(|cache| cache.query(command));
+
     if args.delete_entry {
         if let Some(ref mut cache) = cache {
             cache.delete(command);
         }
     }
 
+    let resolver = resolver::chain(&args.resolvers);
+    // Scripts, cron jobs and editors cannot answer the picker. Pickers draw
+    // on stderr or the terminal, so stdout may be captured, as in `$(, -x cmd)`
+    let interactive = io::stdin().is_terminal() && io::stderr().is_terminal();
+
     if args.print_packages {
-        match index_database(command) {
-            Some(derivations) => {
-                println!(
-                    "Packages that contain /bin/{command}:\n{}",
-                    derivations
-                        .iter()
-                        .map(|a| format!("- {a}"))
-                        .collect::<Box<[String]>>()
-                        .join("\n")
-                );
+        let Some(candidates) = index_database(
+            &resolver,
+            &mut cache,
+            command,
+            !args.retry_unknown,
+            previous.as_ref(),
+        ) else {
+            return ExitCode::FAILURE;
+        };
 
-                return ExitCode::SUCCESS;
+        let chosen = cache.as_ref().and_then(|cache| cache.query(command));
+        println!(
+            "Packages that contain /bin/{command}:\n{}",
+            ranked(command, &candidates, chosen.as_ref())
+                .iter()
+                .map(|a| match &chosen {
+                    Some(entry) if entry.derivation == *a => format!("- {a} (chosen)"),
+                    _ => format!("- {a}"),
+                })
+                .collect::<Box<[String]>>()
+                .join("\n")
+        );
+
+        // Keep the candidates for the next time
+        if let (Some(cache), Some(entry)) = (cache.as_mut(), chosen) {
+            if entry.candidates.as_ref() != Some(&candidates) {
+                let candidates = Some(candidates);
+                cache.update(
+                    command,
+                    CacheEntry {
+                        candidates,
+                        ..entry
+                    },
+                );
             }
-            None => return ExitCode::FAILURE,
         }
+
+        return ExitCode::SUCCESS;
     }
 
-    let entry = match cache {
-        Some(ref mut cache) => cache.query(command).or_else(|| {
-            index_database_pick(command, &args.picker).map(|derivation| {
-                let entry = CacheEntry {
-                    derivation,
-                    path: None,
-                };
+    let pinned = project
+        .as_ref()
+        .and_then(|project| Some((project.config.packages.get(command)?, &project.path)));
+
+    let entry = if let Some((derivation, path)) = pinned {
+        debug!(
+            "using {derivation} for command '{command}' from {}",
+            path.display()
+        );
+        // The project's choice must neither use nor overwrite whatever the
+        // user picked for this command, so its path is cached separately
+        cache = cache.and_then(|_| {
+            let scope = Scope::new(source.clone());
+            let cache = if args.frozen {
+                Cache::new_project_read_only(scope)
+            } else {
+                Cache::new_project(scope)
+            };
+            let mut cache = cache
+                .inspect_err(|e| error!("failed to initialize project cache: {e}"))
+                .ok()?;
+            print_warnings(&cache);
+            cache.set_gc_roots(args.gc_roots);
+            Some(cache)
+        });
+        cache
+            .as_ref()
+            .and_then(|cache| cache.query(command))
+            .filter(|entry| entry.derivation == *derivation)
+            .or_else(|| Some(CacheEntry::chosen(derivation.clone(), Method::Project)))
+    } else {
+        let configured = config::package_for(&config_files, command);
+        let cached = cache
+            .as_ref()
+            .filter(|_| !args.repick)
+            .and_then(|cache| cache.query(command))
+            // A configured package replaces whatever was picked before
+            .filter(|entry| {
+                configured.is_none_or(|(derivation, _)| entry.derivation == derivation)
+            });
+
+        if cached.is_some() {
+            cached
+        } else {
+            let chosen = match configured {
+                Some((derivation, path)) => {
+                    debug!(
+                        "using {derivation} for command '{command}' from {}",
+                        path.display()
+                    );
+                    Some(CacheEntry::chosen(derivation.to_owned(), Method::Config))
+                }
+                None if args.frozen => {
+                    eprintln!("`{command}` was never resolved, and --frozen forbids resolving it.");
+                    None
+                }
+                None => match index_database_pick(
+                    &resolver,
+                    &mut cache,
+                    command,
+                    &Selection {
+                        select: args.select.resolve(interactive),
+                        picker: &args.picker,
+                        source: &source,
+                    },
+                    !args.retry_unknown,
+                    previous.as_ref(),
+                ) {
+                    Ok(entry) => Some(CacheEntry {
+                        // Choosing again keeps the entry pinned
+                        pinned: args.repick && previous.as_ref().is_some_and(|p| p.pinned),
+                        ..entry
+                    }),
+                    Err(code) => return code,
+                },
+            };
+            if let (Some(cache), Some(entry)) = (cache.as_mut(), &chosen) {
                 cache.update(command, entry.clone());
-                entry
-            })
-        }),
-        None => index_database_pick(command, &args.picker).map(|derivation| CacheEntry {
-            derivation,
-            path: None,
-        }),
+            }
+            chosen
+        }
     };
 
     let entry = match entry {
         Some(d) if args.cache_level >= 2 => d,
         Some(d) => {
             debug!("cache_level={}, ignoring path from cache", args.cache_level);
-            CacheEntry {
-                derivation: d.derivation.clone(),
-                path: None,
-            }
+            d.without_path()
         }
+        None if args.frozen => return ExitCode::from(EXIT_NOT_RESOLVED),
         None => return ExitCode::FAILURE,
     };
 
-    let basename = entry.derivation.rsplit('.').next_back().unwrap();
+    let entry = if cache.is_some() && entry.is_expired(args.path_ttl) {
+        match args.refresh {
+            // The background process would write to a frozen cache, and only
+            // knows about the user's cache
+            Refresh::Background if !args.frozen && pinned.is_none() => {
+                debug!("path for command '{command}' expired, refreshing it in the background");
+                spawn_refresh(&source, command, args.gc_roots);
+                entry
+            }
+            _ => {
+                debug!("path for command '{command}' expired, resolving it again");
+                entry.without_path()
+            }
+        }
+    } else {
+        entry
+    };
 
-    let use_channel = env::var("NIX_PATH")
-        .unwrap_or_default()
-        .contains("nixpkgs=");
+    let basename = entry.derivation.rsplit('.').next_back().unwrap();
 
     if args.install {
         let _ = Command::new("nix-env")
@@ -351,32 +725,26 @@ fn main() -> ExitCode {
     } else if args.shell {
         // TODO: use cache here, but this is tricky since it actually depends in `nix-shell`
         let shell_cmd = shell::select_shell_from_pid(process::id()).unwrap_or("bash".into());
-        let _ = run_command_or_open_shell(
-            use_channel,
-            &entry.derivation,
-            &shell_cmd,
-            &[],
-            &args.nixpkgs_flake,
-        )
-        .exec();
+        let _ = nix::shell_command(&source, &entry.derivation, &shell_cmd, &[]).exec();
     } else if args.print_path {
-        let path = get_command_path_from_cache(
-            &mut cache,
-            &entry,
-            use_channel,
-            command,
-            &args.nixpkgs_flake,
-        );
+        let Some(path) = command_path(&mut cache, &source, command, &entry) else {
+            return ExitCode::FAILURE;
+        };
+        if let Some(ref mut cache) = cache {
+            cache.touch(command);
+        }
+        if !args.frozen {
+            record_usage(command, &entry.derivation);
+        }
         println!("{path}");
-    } else if args.subcmds.is_some() {
+    } else if matches!(args.subcmds, Some(SubCmds::Man(_))) {
         // Open manpage via
         // nix shell nixpkgs#drvName --command man commandName
-        let err = run_command_or_open_shell(
-            use_channel,
+        let err = nix::shell_command(
+            &source,
             &entry.derivation.replace(".out", "^*"),
             "man",
             &[command.to_string()],
-            &args.nixpkgs_flake,
         )
         .exec();
 
@@ -384,15 +752,18 @@ fn main() -> ExitCode {
         eprintln!("{err:?}");
         return ExitCode::FAILURE;
     } else {
-        let mut run_cmd = run_command_from_cache(
-            &mut cache,
-            &entry,
-            use_channel,
-            command,
-            trail,
-            &args.nixpkgs_flake,
-        );
+        let Some(mut run_cmd) =
+            run_command_from_cache(&mut cache, &source, command, trail, &entry, alias)
+        else {
+            return ExitCode::FAILURE;
+        };
 
+        if let Some(ref mut cache) = cache {
+            cache.touch(command);
+        }
+        if !args.frozen {
+            record_usage(command, &entry.derivation);
+        }
         // Drop cache before calling exec() to make sure that
         // the cache file is written
         drop(cache);
@@ -431,10 +802,24 @@ struct Opt {
     #[clap(short, long)]
     shell: bool,
 
-    /// Picker to use
+    /// Picker to use, or builtin for the one built into comma, which is also
+    /// used when the picker is not installed
     #[clap(short = 'P', long, env = "COMMA_PICKER", default_value = "fzy")]
     picker: String,
 
+    /// Resolvers used to find the packages containing the executable, tried in
+    /// order until one finds it: database, nix-locate, mapping:<file> (a TOML
+    /// file of `command = "attribute"` pairs) or command:<program> (prints
+    /// the attributes providing the command given as its argument)
+    #[clap(
+        short = 'R',
+        long = "resolver",
+        env = "COMMA_RESOLVERS",
+        value_delimiter = ',',
+        default_value = "database"
+    )]
+    resolvers: Vec<ResolverSpec>,
+
     /// Nixpkgs flake to use
     #[clap(
         short = 'F',
@@ -456,6 +841,18 @@ struct Opt {
     #[clap(short = 'p', long = "print-packages")]
     print_packages: bool,
 
+    /// Print the version, description, license, presence in the local store
+    /// and closure size of a package, e.g. hello.out, then exit. Used to
+    /// preview packages in fzf.
+    #[clap(long, value_name = "ATTRIBUTE")]
+    describe: Option<String>,
+
+    /// Print the effective settings and where each one came from, then exit.
+    /// Settings are read from the command line, then the environment, then
+    /// $XDG_CONFIG_HOME/comma/config.toml, then /etc/comma/config.toml.
+    #[clap(long)]
+    show_config: bool,
+
     /// Print the absolute path to the executable in the nix store
     #[clap(short = 'x', long = "print-path")]
     print_path: bool,
@@ -465,7 +862,45 @@ struct Opt {
     #[clap(long = "cache-level", env = "COMMA_CACHING", default_value_t = 2)]
     cache_level: u8,
 
-    /// Empty the cache
+    /// How long a cached path is used before the package is resolved again,
+    /// e.g. 12h or 7d, or never
+    #[clap(long = "path-ttl", env = "COMMA_PATH_TTL", default_value = "never")]
+    path_ttl: Ttl,
+
+    /// When to resolve an expired path again: foreground (before running the
+    /// command) or background (for the next run, using the expired path now)
+    #[clap(long, env = "COMMA_REFRESH", default_value = "foreground")]
+    refresh: Refresh,
+
+    /// Protect cached paths from garbage collection with GC roots in the
+    /// comma state directory, see `comma cache gc`
+    #[clap(long = "gc-roots", env = "COMMA_GC_ROOTS")]
+    gc_roots: bool,
+
+    /// Look the command up even if it was not found in the same nix-index
+    /// database before
+    #[clap(long = "retry-unknown")]
+    retry_unknown: bool,
+
+    /// Only run commands that are in the cache or configured, without ever
+    /// writing to the cache or showing the picker. Exits with status 3 for
+    /// any other command.
+    #[clap(long, env = "COMMA_FROZEN")]
+    frozen: bool,
+
+    /// How to choose among several packages when nothing was chosen before:
+    /// pick (show the picker), first, heuristic (the best package judging by
+    /// its name, the same on every machine), fail, cached-only
+    /// (fail even with a single package), or auto (pick if stdin and stderr
+    /// are terminals, otherwise fail). Refusing exits with status 3.
+    #[clap(long, env = "COMMA_SELECT", default_value = "auto")]
+    select: Select,
+
+    /// Resolve the cached path of the command again, then exit
+    #[clap(long = "refresh-path", hide = true)]
+    refresh_path: bool,
+
+    /// Empty the cache, except for pinned entries
     #[clap(short, long = "empty-cache")]
     empty_cache: bool,
 
@@ -474,8 +909,13 @@ struct Opt {
     #[clap(short, long = "delete-entry")]
     delete_entry: bool,
 
+    /// Choose the package for the command again, starting from the previous
+    /// choice
+    #[clap(long)]
+    repick: bool,
+
     /// Command to run
-    #[clap(required_unless_present_any = ["empty_cache", "mangen", "print_completions"], name = "cmd", value_hint = ValueHint::Other)]
+    #[clap(required_unless_present_any = ["empty_cache", "mangen", "print_completions", "describe", "show_config"], name = "cmd", value_hint = ValueHint::Other)]
     cmd: Vec<String>,
 
     #[clap(subcommand)]
@@ -489,6 +929,9 @@ enum SubCmds {
     ///
     /// Currently only supports Section 1 pages for programs.
     Man(ManArgs),
+
+    /// Inspect and edit the cache of choices and paths
+    Cache(cache_cmd::CacheArgs),
 }
 
 #[derive(Args)]
@@ -0,0 +1,349 @@
+use std::{
+    env,
+    error::Error,
+    fmt,
+    path::Path,
+    process::{Command, Stdio},
+};
+
+use log::{debug, trace};
+use serde::{Deserialize, Serialize};
+
+type ResultDyn<T> = Result<T, Box<dyn Error>>;
+
+const CHANNEL: &str = "<nixpkgs>";
+
+/// Where packages are taken from
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(from = "String", into = "String")]
+pub enum Source {
+    /// `<nixpkgs>` from `NIX_PATH`
+    Channel,
+    /// A nixpkgs flake reference, e.g. `nixpkgs` or `github:NixOS/nixpkgs/nixos-24.05`
+    Flake(String),
+}
+
+impl Source {
+    /// Use the `<nixpkgs>` channel if `NIX_PATH` points to one, otherwise the
+    /// given flake
+    pub fn from_env(nixpkgs_flake: &str) -> Self {
+        let use_channel = env::var("NIX_PATH")
+            .unwrap_or_default()
+            .contains("nixpkgs=");
+
+        if use_channel {
+            Source::Channel
+        } else {
+            Source::Flake(nixpkgs_flake.to_owned())
+        }
+    }
+
+    /// Arguments selecting `attribute` for `nix shell` or `nix build`
+    fn installable(&self, attribute: &str) -> Vec<String> {
+        match self {
+            Source::Channel => vec!["-f".into(), CHANNEL.into(), attribute.into()],
+            Source::Flake(flake) => vec![format!("{flake}#{attribute}")],
+        }
+    }
+}
+
+/// Written as `<nixpkgs>` for the channel, or as the flake reference
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Channel => f.write_str(CHANNEL),
+            Source::Flake(flake) => f.write_str(flake),
+        }
+    }
+}
+
+impl From<String> for Source {
+    fn from(s: String) -> Self {
+        if s == CHANNEL {
+            Source::Channel
+        } else {
+            Source::Flake(s)
+        }
+    }
+}
+
+impl From<Source> for String {
+    fn from(source: Source) -> Self {
+        source.to_string()
+    }
+}
+
+/// The nix system comma runs on, e.g. `x86_64-linux` or `aarch64-darwin`
+pub fn current_system() -> String {
+    let arch = match env::consts::ARCH {
+        "x86" => "i686",
+        "arm" => "armv7l",
+        "powerpc64" if cfg!(target_endian = "little") => "powerpc64le",
+        arch => arch,
+    };
+    let os = match env::consts::OS {
+        "macos" => "darwin",
+        os => os,
+    };
+    format!("{arch}-{os}")
+}
+
+/// `nix shell` command running `command` with `trail` as its arguments from
+/// the package `attribute`, or opening an interactive shell with the package
+/// if `command` is empty
+pub fn shell_command(source: &Source, attribute: &str, command: &str, trail: &[String]) -> Command {
+    let mut run_cmd = Command::new("nix");
+
+    run_cmd.args([
+        "--extra-experimental-features",
+        "nix-command flakes",
+        "shell",
+    ]);
+
+    run_cmd.args(source.installable(attribute));
+
+    if !command.is_empty() {
+        run_cmd.args(["--command", command]);
+        if !trail.is_empty() {
+            run_cmd.args(trail);
+        }
+    };
+
+    trace!("run nix command arguments: {run_cmd:?}");
+
+    run_cmd
+}
+
+/// Build the package `attribute` and return the path to `command` in it. With
+/// `gc_root`, the package is protected from garbage collection by an indirect
+/// GC root at that location.
+pub fn build_command_path(
+    source: &Source,
+    attribute: &str,
+    command: &str,
+    gc_root: Option<&Path>,
+) -> ResultDyn<String> {
+    let mut run_cmd = Command::new("nix");
+
+    run_cmd.args([
+        "--extra-experimental-features",
+        "nix-command flakes",
+        "build",
+        "--print-out-paths",
+    ]);
+
+    match gc_root {
+        Some(gc_root) => run_cmd.arg("--out-link").arg(gc_root),
+        None => run_cmd.arg("--no-link"),
+    };
+
+    run_cmd.args(source.installable(attribute));
+
+    let result = run_cmd
+        .stdout(Stdio::piped())
+        .spawn()
+        .map_err(|err| format!("failed to execute nix: {err}"))?
+        .wait_with_output()?;
+
+    if !result.status.success() {
+        return Err(format!("failed to build {attribute}").into());
+    }
+
+    // It is safe to assume that only one path will be printed because
+    // nix-locate appends the output to the derivation name
+    // (e.g., firefox.out instead of firefox)
+    let base_path = std::str::from_utf8(&result.stdout)
+        .map_err(|err| format!("nix outputted invalid UTF-8: {err}"))?
+        .trim();
+
+    // It is safe to assume that command is in $out/bin/{command} from
+    // the derivation, since this was already filtered by nix-locate
+    Ok(format!("{base_path}/bin/{command}"))
+}
+
+/// Protect the store path providing the executable at `path` from garbage
+/// collection with an indirect GC root at `gc_root`
+pub fn add_gc_root(path: &str, gc_root: &Path) -> ResultDyn<()> {
+    // The executable is at $out/bin/{command}
+    let store_path = Path::new(path)
+        .parent()
+        .and_then(Path::parent)
+        .ok_or_else(|| format!("'{path}' is not in a store path"))?;
+
+    let status = Command::new("nix")
+        .args([
+            "--extra-experimental-features",
+            "nix-command flakes",
+            "build",
+            "--out-link",
+        ])
+        .arg(gc_root)
+        .arg(store_path)
+        .stdout(Stdio::null())
+        .status()
+        .map_err(|err| format!("failed to execute nix: {err}"))?;
+
+    if !status.success() {
+        return Err(format!("failed to add GC root for {}", store_path.display()).into());
+    }
+
+    Ok(())
+}
+
+/// Version of the package providing the executable at `path`, e.g. `2.12.1`
+/// for `/nix/store/<hash>-hello-2.12.1/bin/hello`. Like `builtins.parseDrvName`,
+/// the version starts after the first dash not followed by a letter.
+pub fn version_from_path(path: &str) -> Option<String> {
+    let name = Path::new(path).parent()?.parent()?.file_name()?.to_str()?;
+    let name = match name.split_once('-') {
+        Some((hash, name)) if hash.len() == 32 => name,
+        _ => name,
+    };
+
+    let (start, _) = name.match_indices('-').find(|(i, _)| {
+        name[i + 1..]
+            .chars()
+            .next()
+            .is_some_and(|c| !c.is_ascii_alphabetic())
+    })?;
+    Some(name[start + 1..].to_owned())
+}
+
+/// Pin `source` to what it currently refers to. Returns the source to build
+/// from and the revision of nixpkgs in it, if it can be determined. A flake is
+/// resolved to its locked reference, so that the revision is the one built even
+/// if the flake registry or the branch moves on meanwhile.
+pub fn lock(source: &Source) -> (Source, Option<String>) {
+    let mut run_cmd = Command::new("nix");
+
+    run_cmd.args(["--extra-experimental-features", "nix-command flakes"]);
+
+    match source {
+        Source::Channel => run_cmd.args([
+            "eval",
+            "--raw",
+            "--impure",
+            "--expr",
+            "(import <nixpkgs/lib>).trivial.revisionWithDefault \"\"",
+        ]),
+        Source::Flake(flake) => run_cmd.args(["flake", "metadata", "--json", flake]),
+    };
+
+    let output = run_cmd
+        .stderr(Stdio::null())
+        .output()
+        .inspect_err(|err| debug!("failed to execute nix: {err}"))
+        .ok()
+        .filter(|output| output.status.success());
+    let Some(output) = output else {
+        return (source.clone(), None);
+    };
+
+    match source {
+        Source::Channel => {
+            let revision = String::from_utf8(output.stdout).ok();
+            (source.clone(), revision.filter(|r| !r.is_empty()))
+        }
+        Source::Flake(_) => {
+            let metadata: serde_json::Value =
+                serde_json::from_slice(&output.stdout).unwrap_or_default();
+            let revision = metadata
+                .get("revision")
+                .or_else(|| metadata.get("locked")?.get("rev"))
+                .and_then(|rev| rev.as_str())
+                .map(str::to_owned);
+            // Flakes without a revision, e.g. local paths, are built as given
+            let locked = metadata
+                .get("url")
+                .and_then(|url| url.as_str())
+                .filter(|_| revision.is_some())
+                .map_or_else(|| source.clone(), |url| Source::Flake(url.to_owned()));
+            (locked, revision)
+        }
+    }
+}
+
+/// Applied to a package to describe it, see [`describe`]. Evaluating the out
+/// path fails for broken or unfree packages, which are still described.
+const DESCRIBE_EXPR: &str = r#"p:
+let
+  license = p.meta.license or [ ];
+  licenses = if builtins.isList license then license else [ license ];
+  name = l: if builtins.isAttrs l then l.spdxId or l.shortName or l.fullName or "unknown" else toString l;
+  outPath = builtins.tryEval (p.outPath or null);
+in
+{
+  version = p.version or null;
+  description = p.meta.description or null;
+  licenses = map name licenses;
+  outPath = if outPath.success then outPath.value else null;
+}"#;
+
+/// Metadata of a package, as evaluated from nixpkgs
+#[derive(Deserialize, Debug)]
+pub struct Description {
+    pub version: Option<String>,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub licenses: Vec<String>,
+    #[serde(rename = "outPath")]
+    pub out_path: Option<String>,
+}
+
+/// Evaluate the metadata of the package `attribute`, e.g. `hello.out`
+pub fn describe(source: &Source, attribute: &str) -> ResultDyn<Description> {
+    let mut run_cmd = Command::new("nix");
+
+    run_cmd.args([
+        "--extra-experimental-features",
+        "nix-command flakes",
+        "eval",
+        "--json",
+    ]);
+    run_cmd.args(source.installable(attribute));
+    run_cmd.args(["--apply", DESCRIBE_EXPR]);
+
+    trace!("describe nix command arguments: {run_cmd:?}");
+
+    let output = run_cmd
+        .stderr(Stdio::piped())
+        .output()
+        .map_err(|err| format!("failed to execute nix: {err}"))?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("failed to evaluate {attribute}: {}", stderr.trim()).into());
+    }
+
+    Ok(serde_json::from_slice(&output.stdout)
+        .map_err(|err| format!("nix outputted invalid metadata: {err}"))?)
+}
+
+/// Size in bytes of the closure of the store path `path`, if it is in the
+/// local store
+pub fn closure_size(path: &str) -> Option<u64> {
+    let output = Command::new("nix")
+        .args([
+            "--extra-experimental-features",
+            "nix-command flakes",
+            "path-info",
+            "--closure-size",
+            "--json",
+            path,
+        ])
+        .stderr(Stdio::null())
+        .output()
+        .inspect_err(|err| debug!("failed to execute nix: {err}"))
+        .ok()
+        .filter(|output| output.status.success())?;
+
+    // Older versions of nix print a list of paths, newer ones an object keyed
+    // by path
+    let info: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+    let info = match &info {
+        serde_json::Value::Array(paths) => paths.first()?,
+        serde_json::Value::Object(paths) => paths.values().next()?,
+        _ => return None,
+    };
+    info.get("closureSize")?.as_u64()
+}
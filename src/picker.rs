@@ -0,0 +1,277 @@
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    sync::mpsc::{self, Receiver, Sender},
+    thread,
+    time::Duration,
+};
+
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    queue,
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, ClearType},
+};
+
+/// Most choices shown at once
+const HEIGHT: usize = 10;
+
+/// Most lines of preview shown below the choices
+const PREVIEW_HEIGHT: usize = 6;
+
+/// Describes a choice in a few lines, shown below the choices while it is
+/// selected
+pub type Preview = Box<dyn Fn(&str) -> Vec<String> + Send>;
+
+/// Runs the preview in the background, so that the picker stays responsive
+/// while it is slow
+struct Previewer {
+    requests: Sender<String>,
+    results: Receiver<(String, Vec<String>)>,
+    previews: HashMap<String, Vec<String>>,
+    requested: Option<String>,
+}
+
+impl Previewer {
+    fn spawn(preview: Preview) -> Self {
+        let (requests, pending) = mpsc::channel::<String>();
+        let (done, results) = mpsc::channel();
+
+        // Left running when the picker returns, the process will not wait for
+        // the preview that was being computed
+        thread::spawn(move || {
+            while let Ok(mut choice) = pending.recv() {
+                // Only the latest selection is worth previewing
+                while let Ok(newer) = pending.try_recv() {
+                    choice = newer;
+                }
+                let lines = preview(&choice);
+                if done.send((choice, lines)).is_err() {
+                    break;
+                }
+            }
+        });
+
+        Self {
+            requests,
+            results,
+            previews: HashMap::new(),
+            requested: None,
+        }
+    }
+
+    /// The preview of `choice`, requesting it if it is not known yet
+    fn get(&mut self, choice: &str) -> Option<&[String]> {
+        if !self.previews.contains_key(choice) && self.requested.as_deref() != Some(choice) {
+            let _ = self.requests.send(choice.to_owned());
+            self.requested = Some(choice.to_owned());
+        }
+        self.previews.get(choice).map(Vec::as_slice)
+    }
+
+    /// Collect the finished previews, returning whether there were any
+    fn receive(&mut self) -> bool {
+        let mut received = false;
+        while let Ok((choice, lines)) = self.results.try_recv() {
+            self.previews.insert(choice, lines);
+            received = true;
+        }
+        received
+    }
+}
+
+/// Leaves raw mode when dropped, even if the picker fails
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> io::Result<Self> {
+        terminal::enable_raw_mode()?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Whether the characters of `query` appear in `choice` in order, ignoring
+/// case
+fn matches(choice: &str, query: &str) -> bool {
+    let mut chars = choice.chars().flat_map(char::to_lowercase);
+    query
+        .chars()
+        .flat_map(char::to_lowercase)
+        .all(|q| chars.any(|c| c == q))
+}
+
+struct Picker<'a> {
+    choices: &'a [String],
+    query: String,
+    /// Index of the selected choice among the matching ones
+    selected: usize,
+    previewer: Option<Previewer>,
+}
+
+impl<'a> Picker<'a> {
+    fn matching(&self) -> Vec<&'a String> {
+        self.choices
+            .iter()
+            .filter(|choice| matches(choice, &self.query))
+            .collect()
+    }
+
+    /// Draw the query and the visible choices below the cursor, then put the
+    /// cursor back at the end of the query
+    fn render(&mut self, out: &mut impl Write, matching: &[&String]) -> io::Result<()> {
+        let width = match terminal::size() {
+            Ok((cols, _)) if cols > 1 => usize::from(cols),
+            _ => 80,
+        };
+        let offset = self.selected.saturating_sub(HEIGHT - 1);
+
+        queue!(
+            out,
+            cursor::MoveToColumn(0),
+            terminal::Clear(ClearType::FromCursorDown),
+            Print(format!("> {}", self.query))
+        )?;
+
+        let visible = matching.iter().enumerate().skip(offset).take(HEIGHT);
+        for (i, choice) in visible.clone() {
+            // Wrapped lines would break moving back up
+            let line: String = choice.chars().take(width - 1).collect();
+            queue!(out, Print("\r\n"))?;
+            if i == self.selected {
+                queue!(
+                    out,
+                    SetAttribute(Attribute::Reverse),
+                    Print(line),
+                    SetAttribute(Attribute::Reset)
+                )?;
+            } else {
+                queue!(out, Print(line))?;
+            }
+        }
+
+        let mut shown = visible.count();
+
+        if let Some(previewer) = &mut self.previewer {
+            let loading = ["...".to_owned()];
+            let preview = match matching.get(self.selected) {
+                Some(choice) => previewer.get(choice).unwrap_or(&loading),
+                None => &[],
+            };
+            for line in preview.iter().take(PREVIEW_HEIGHT) {
+                let line: String = line.chars().take(width - 1).collect();
+                queue!(
+                    out,
+                    Print("\r\n"),
+                    SetAttribute(Attribute::Dim),
+                    Print(line),
+                    SetAttribute(Attribute::Reset)
+                )?;
+                shown += 1;
+            }
+        }
+
+        if shown > 0 {
+            queue!(out, cursor::MoveUp(shown as u16))?;
+        }
+        let column = 2 + self.query.chars().count();
+        queue!(out, cursor::MoveToColumn(column.min(width - 1) as u16))?;
+        out.flush()
+    }
+
+    /// Wait for a key press. Returns `None` when the picker must be drawn
+    /// again first, because a preview arrived or the terminal was resized.
+    fn next_key(&mut self) -> io::Result<Option<KeyEvent>> {
+        loop {
+            if event::poll(Duration::from_millis(50))? {
+                return Ok(match event::read()? {
+                    Event::Key(key) if key.kind != KeyEventKind::Release => Some(key),
+                    Event::Key(_) => continue,
+                    _ => None,
+                });
+            }
+            if self.previewer.as_mut().is_some_and(Previewer::receive) {
+                return Ok(None);
+            }
+        }
+    }
+
+    fn run(&mut self, out: &mut impl Write) -> io::Result<Option<String>> {
+        // Make room below the prompt, so that drawing never scrolls
+        let mut height = self.choices.len().min(HEIGHT);
+        if self.previewer.is_some() {
+            height += PREVIEW_HEIGHT;
+        }
+        queue!(out, Print("\r\n".repeat(height)))?;
+        if height > 0 {
+            queue!(out, cursor::MoveUp(height as u16))?;
+        }
+
+        loop {
+            let matching = self.matching();
+            self.selected = self.selected.min(matching.len().saturating_sub(1));
+            self.render(out, &matching)?;
+
+            let Some(key) = self.next_key()? else {
+                continue;
+            };
+            let ctrl = key.modifiers.contains(KeyModifiers::CONTROL);
+
+            match key.code {
+                KeyCode::Enter => return Ok(matching.get(self.selected).map(|c| c.to_string())),
+                KeyCode::Esc => return Ok(None),
+                KeyCode::Char('c' | 'd') if ctrl => return Ok(None),
+                KeyCode::Up | KeyCode::BackTab => self.selected = self.selected.saturating_sub(1),
+                KeyCode::Char('p' | 'k') if ctrl => self.selected = self.selected.saturating_sub(1),
+                KeyCode::Down | KeyCode::Tab => self.selected += 1,
+                KeyCode::Char('n' | 'j') if ctrl => self.selected += 1,
+                KeyCode::Char('u') if ctrl => {
+                    self.query.clear();
+                    self.selected = 0;
+                }
+                KeyCode::Backspace => {
+                    self.query.pop();
+                    self.selected = 0;
+                }
+                KeyCode::Char(c) if !ctrl => {
+                    self.query.push(c);
+                    self.selected = 0;
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// Let the user pick one of `choices` in the terminal, starting on the first.
+/// Typing filters the choices, the arrow keys move the selection, Enter picks
+/// it and Escape cancels. The `preview` of the selected choice is shown below
+/// the choices. Drawn on stderr, so that stdout can be captured.
+pub fn pick(choices: &[String], preview: Option<Preview>) -> io::Result<Option<String>> {
+    let mut out = io::stderr();
+    let raw_mode = RawMode::enable()?;
+
+    let mut picker = Picker {
+        choices,
+        query: String::new(),
+        selected: 0,
+        previewer: preview.map(Previewer::spawn),
+    };
+    let picked = picker.run(&mut out);
+
+    let _ = queue!(
+        out,
+        cursor::MoveToColumn(0),
+        terminal::Clear(ClearType::FromCursorDown)
+    );
+    let _ = out.flush();
+    drop(raw_mode);
+
+    picked
+}
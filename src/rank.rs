@@ -0,0 +1,140 @@
+//! Orders the packages providing a command from the most to the least likely
+//! to be the one wanted.
+
+use std::{
+    cmp::Reverse,
+    collections::{HashMap, HashSet},
+    path::Path,
+};
+
+/// Attributes among `store_paths` whose store path exists in the local store
+pub fn in_store(store_paths: &HashMap<String, String>) -> HashSet<String> {
+    store_paths
+        .iter()
+        .filter(|(_, path)| Path::new(path).exists())
+        .map(|(attribute, _)| attribute.clone())
+        .collect()
+}
+
+/// How likely `attribute` is to provide `command`, judging by the attribute
+/// alone: attributes named like the command first, top-level attributes
+/// before ones in package sets, `out` before `bin` before other outputs
+fn attribute_key(command: &str, attribute: &str) -> (bool, bool, u8) {
+    let (path, output) = attribute.rsplit_once('.').unwrap_or((attribute, ""));
+    let name = path.rsplit('.').next().unwrap_or(path);
+    (
+        name != command,
+        path.contains('.'),
+        match output {
+            "out" => 0,
+            "bin" => 1,
+            _ => 2,
+        },
+    )
+}
+
+/// Sort the attributes providing `command`, best first, by the attributes
+/// alone, so that the order is the same on every machine. Ties keep their
+/// order.
+pub fn rank_by_attribute(command: &str, attributes: &mut [String]) {
+    attributes.sort_by_cached_key(|attribute| attribute_key(command, attribute));
+}
+
+/// Sort the attributes providing `command`, best first: the `previous`
+/// choice, then by `frecency` (see [`crate::usage`]), then as
+/// [`rank_by_attribute`] does, then attributes in `in_store`. Ties keep their
+/// order.
+pub fn rank(
+    command: &str,
+    attributes: &mut [String],
+    previous: Option<&str>,
+    frecency: &HashMap<String, u64>,
+    in_store: &HashSet<String>,
+) {
+    attributes.sort_by_cached_key(|attribute| {
+        (
+            previous != Some(attribute.as_str()),
+            Reverse(frecency.get(attribute).copied().unwrap_or_default()),
+            attribute_key(command, attribute),
+            !in_store.contains(attribute),
+        )
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn attributes(attributes: &[&str]) -> Vec<String> {
+        attributes.iter().map(|&a| a.to_owned()).collect()
+    }
+
+    #[test]
+    fn ranks_by_attribute() {
+        let mut attrs = attributes(&[
+            "python3Packages.foo.out",
+            "foo.bin",
+            "python3.bin",
+            "python3.out",
+        ]);
+        rank_by_attribute("python3", &mut attrs);
+        assert_eq!(
+            attrs,
+            [
+                "python3.out",
+                "python3.bin",
+                "foo.bin",
+                "python3Packages.foo.out"
+            ]
+        );
+    }
+
+    #[test]
+    fn ranks_the_previous_choice_first() {
+        let mut attrs = attributes(&["python3.out", "python3Packages.python.out", "pypy.out"]);
+        let frecency = HashMap::from([("pypy.out".to_owned(), 10)]);
+        rank(
+            "python3",
+            &mut attrs,
+            Some("python3Packages.python.out"),
+            &frecency,
+            &HashSet::new(),
+        );
+        assert_eq!(
+            attrs,
+            ["python3Packages.python.out", "pypy.out", "python3.out"]
+        );
+    }
+
+    #[test]
+    fn breaks_ties_with_the_store() {
+        let mut attrs = attributes(&["gcc.out", "clang.out", "tcc.out"]);
+        let in_store = HashSet::from(["tcc.out".to_owned()]);
+        rank("cc", &mut attrs, None, &HashMap::new(), &in_store);
+        assert_eq!(attrs, ["tcc.out", "gcc.out", "clang.out"]);
+
+        // Only a tiebreak: the attribute still comes first
+        let mut attrs = attributes(&["cc.bin", "cc.out"]);
+        let in_store = HashSet::from(["cc.bin".to_owned()]);
+        rank("cc", &mut attrs, None, &HashMap::new(), &in_store);
+        assert_eq!(attrs, ["cc.out", "cc.bin"]);
+    }
+
+    #[test]
+    fn finds_store_paths_that_exist() {
+        let store_paths = HashMap::from([
+            (
+                "here.out".to_owned(),
+                std::env::temp_dir().display().to_string(),
+            ),
+            (
+                "gone.out".to_owned(),
+                "/nix/store/hgone-gone-1.0".to_owned(),
+            ),
+        ]);
+        assert_eq!(
+            in_store(&store_paths),
+            HashSet::from(["here.out".to_owned()])
+        );
+    }
+}
@@ -0,0 +1,306 @@
+use std::{
+    collections::HashMap, error::Error, fmt, fs, path::PathBuf, process::Command, str::FromStr,
+};
+
+use log::debug;
+use serde::Deserialize;
+
+use crate::{database, index, Candidate};
+
+type ResultDyn<T> = Result<T, Box<dyn Error>>;
+
+/// Finds the packages providing an executable
+pub trait Resolver {
+    /// Name used to refer to the resolver in messages
+    fn name(&self) -> String;
+
+    /// Returns the packages (e.g. `hello.out`) providing `/bin/{command}`,
+    /// or an empty list if the resolver does not know the command
+    fn resolve(&self, command: &str) -> ResultDyn<Vec<Candidate>>;
+
+    /// Whether the answers only depend on the nix-index database
+    fn uses_database(&self) -> bool {
+        false
+    }
+}
+
+/// Runs `nix-locate` against the nix-index database
+pub struct NixLocate;
+
+impl Resolver for NixLocate {
+    fn name(&self) -> String {
+        "nix-locate".into()
+    }
+
+    fn uses_database(&self) -> bool {
+        true
+    }
+
+    fn resolve(&self, command: &str) -> ResultDyn<Vec<Candidate>> {
+        let nix_locate_output = Command::new("nix-locate")
+            .args(["--minimal", "--at-root", "--whole-name"])
+            .arg(format!("/bin/{command}"))
+            .output()
+            .map_err(|err| format!("failed to execute nix-locate: {err}"))?;
+
+        if !nix_locate_output.status.success() {
+            return Err(match std::str::from_utf8(&nix_locate_output.stderr) {
+                Ok(stderr) => format!("nix-locate failed with: {stderr}").into(),
+                Err(_) => "nix-locate failed".into(),
+            });
+        }
+
+        Ok(std::str::from_utf8(&nix_locate_output.stdout)?
+            .lines()
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(Candidate::new)
+            .collect())
+    }
+}
+
+/// Reads the nix-index database in-process, falling back to `nix-locate` if
+/// the database uses a format this version of comma does not know
+pub struct Database;
+
+impl Resolver for Database {
+    fn name(&self) -> String {
+        "database".into()
+    }
+
+    fn uses_database(&self) -> bool {
+        true
+    }
+
+    fn resolve(&self, command: &str) -> ResultDyn<Vec<Candidate>> {
+        match database::locate(&index::get_database_file(), command) {
+            Ok(packages) => Ok(packages
+                .into_vec()
+                .into_iter()
+                .map(|package| Candidate {
+                    attribute: package.attribute,
+                    store_path: Some(package.store_path.to_string_lossy().into_owned()),
+                })
+                .collect()),
+            Err(e @ database::Error::UnsupportedFormat(_)) => {
+                debug!("{e}, falling back to nix-locate");
+                NixLocate.resolve(command)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum MappingValue {
+    One(String),
+    Many(Vec<String>),
+}
+
+/// Looks commands up in a TOML file mapping each command to one or more
+/// attributes, e.g. `protoc = "protobuf.out"`
+pub struct Mapping {
+    path: PathBuf,
+}
+
+impl Mapping {
+    pub fn new(path: PathBuf) -> Self {
+        Self { path }
+    }
+}
+
+impl Resolver for Mapping {
+    fn name(&self) -> String {
+        format!("mapping:{}", self.path.display())
+    }
+
+    fn resolve(&self, command: &str) -> ResultDyn<Vec<Candidate>> {
+        let contents = fs::read_to_string(&self.path)
+            .map_err(|e| format!("failed to read '{}': {e}", self.path.display()))?;
+        let mut mapping: HashMap<String, MappingValue> = toml::from_str(&contents)
+            .map_err(|e| format!("failed to parse '{}': {e}", self.path.display()))?;
+
+        let attrs = match mapping.remove(command) {
+            Some(MappingValue::One(attr)) => vec![attr],
+            Some(MappingValue::Many(attrs)) => attrs,
+            None => Vec::new(),
+        };
+        Ok(attrs.into_iter().map(Candidate::new).collect())
+    }
+}
+
+/// Runs an external program with the command as its only argument, reading
+/// one attribute per line from its output. An exit status of 1 with no output
+/// means the command is unknown.
+pub struct External {
+    program: String,
+}
+
+impl External {
+    pub fn new(program: String) -> Self {
+        Self { program }
+    }
+}
+
+impl Resolver for External {
+    fn name(&self) -> String {
+        format!("command:{}", self.program)
+    }
+
+    fn resolve(&self, command: &str) -> ResultDyn<Vec<Candidate>> {
+        let output = Command::new(&self.program)
+            .arg(command)
+            .output()
+            .map_err(|err| format!("failed to execute {}: {err}", self.program))?;
+
+        let unknown = output.status.code() == Some(1) && output.stdout.is_empty();
+        if !output.status.success() && !unknown {
+            return Err(format!(
+                "{} failed with: {}",
+                self.program,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )
+            .into());
+        }
+
+        Ok(std::str::from_utf8(&output.stdout)
+            .map_err(|e| format!("{} outputted invalid UTF-8: {e}", self.program))?
+            .lines()
+            .map(str::trim)
+            .filter(|s| !s.is_empty())
+            .map(Candidate::new)
+            .collect())
+    }
+}
+
+/// Tries each resolver in order, returning the candidates of the first one
+/// that knows the command. Failing resolvers are skipped, their error is only
+/// reported if no other resolver finds the command.
+pub struct Chain(Vec<Box<dyn Resolver>>);
+
+impl Chain {
+    pub fn new(resolvers: Vec<Box<dyn Resolver>>) -> Self {
+        Self(resolvers)
+    }
+}
+
+impl Resolver for Chain {
+    fn name(&self) -> String {
+        self.0
+            .iter()
+            .map(|r| r.name())
+            .collect::<Vec<_>>()
+            .join(",")
+    }
+
+    fn uses_database(&self) -> bool {
+        self.0.iter().all(|resolver| resolver.uses_database())
+    }
+
+    fn resolve(&self, command: &str) -> ResultDyn<Vec<Candidate>> {
+        // Checked here rather than by each resolver, so that the warnings are
+        // printed once even when several resolvers read the database
+        if self.0.iter().any(|resolver| resolver.uses_database()) {
+            index::check_database_updated();
+        }
+
+        let mut first_error = None;
+
+        for resolver in &self.0 {
+            match resolver.resolve(command) {
+                Ok(attrs) if !attrs.is_empty() => {
+                    debug!("resolver {} found {attrs:?}", resolver.name());
+                    return Ok(attrs);
+                }
+                Ok(_) => debug!("resolver {} found nothing", resolver.name()),
+                Err(e) => {
+                    debug!("resolver {} failed: {e}", resolver.name());
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
+
+        match first_error {
+            Some(e) => Err(e),
+            None => Ok(Vec::new()),
+        }
+    }
+}
+
+/// A resolver as written on the command line, e.g. `database` or
+/// `mapping:/etc/comma/packages.toml`
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum ResolverSpec {
+    Database,
+    NixLocate,
+    Mapping(PathBuf),
+    Command(String),
+}
+
+impl ResolverSpec {
+    pub fn build(&self) -> Box<dyn Resolver> {
+        match self {
+            ResolverSpec::Database => Box::new(Database),
+            ResolverSpec::NixLocate => Box::new(NixLocate),
+            ResolverSpec::Mapping(path) => Box::new(Mapping::new(path.clone())),
+            ResolverSpec::Command(program) => Box::new(External::new(program.clone())),
+        }
+    }
+}
+
+impl FromStr for ResolverSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "database" => Ok(ResolverSpec::Database),
+            None if s == "nix-locate" => Ok(ResolverSpec::NixLocate),
+            Some(("mapping", path)) if !path.is_empty() => {
+                Ok(ResolverSpec::Mapping(PathBuf::from(path)))
+            }
+            Some(("command", program)) if !program.is_empty() => {
+                Ok(ResolverSpec::Command(program.to_owned()))
+            }
+            _ => Err(format!(
+                "unknown resolver '{s}', expected one of: database, nix-locate, mapping:<file>, command:<program>"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for ResolverSpec {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for ResolverSpec {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResolverSpec::Database => write!(f, "database"),
+            ResolverSpec::NixLocate => write!(f, "nix-locate"),
+            ResolverSpec::Mapping(path) => write!(f, "mapping:{}", path.display()),
+            ResolverSpec::Command(program) => write!(f, "command:{program}"),
+        }
+    }
+}
+
+/// Whether the chain of resolvers named `name` (see [`Resolver::name`]) only
+/// depends on the nix-index database
+pub fn is_database_chain(name: &str) -> bool {
+    name.split(',').all(|resolver| {
+        matches!(
+            resolver.parse(),
+            Ok(ResolverSpec::Database | ResolverSpec::NixLocate)
+        )
+    })
+}
+
+/// Build a chain out of the given resolvers
+pub fn chain(specs: &[ResolverSpec]) -> Chain {
+    Chain::new(specs.iter().map(ResolverSpec::build).collect())
+}
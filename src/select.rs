@@ -0,0 +1,77 @@
+//! How the package for a command is chosen among several candidates.
+
+use std::{fmt, str::FromStr};
+
+use serde::Deserialize;
+
+/// How to choose among the packages providing a command when nothing was
+/// chosen before
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Select {
+    /// Show the picker in a terminal, otherwise fail
+    Auto,
+    /// Always show the picker
+    Pick,
+    /// Take the first candidate
+    First,
+    /// Take the best candidate judging by its attribute, see
+    /// [`crate::rank::rank_by_attribute`]
+    Heuristic,
+    /// Refuse to choose
+    Fail,
+    /// Only run commands that were already chosen, even with a single
+    /// candidate
+    CachedOnly,
+}
+
+impl Select {
+    /// The strategy `Auto` stands for, depending on whether comma runs in a
+    /// terminal
+    pub fn resolve(self, interactive: bool) -> Self {
+        match self {
+            Select::Auto if interactive => Select::Pick,
+            Select::Auto => Select::Fail,
+            select => select,
+        }
+    }
+}
+
+impl FromStr for Select {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Select::Auto),
+            "pick" => Ok(Select::Pick),
+            "first" => Ok(Select::First),
+            "heuristic" => Ok(Select::Heuristic),
+            "fail" => Ok(Select::Fail),
+            "cached-only" => Ok(Select::CachedOnly),
+            _ => Err(format!(
+                "unknown selection strategy '{s}', expected auto, pick, first, heuristic, fail or cached-only"
+            )),
+        }
+    }
+}
+
+impl TryFrom<String> for Select {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Select {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Select::Auto => "auto",
+            Select::Pick => "pick",
+            Select::First => "first",
+            Select::Heuristic => "heuristic",
+            Select::Fail => "fail",
+            Select::CachedOnly => "cached-only",
+        })
+    }
+}
@@ -0,0 +1,308 @@
+use std::{collections::BTreeMap, path::PathBuf};
+
+use clap::{parser::ValueSource, ArgMatches, CommandFactory};
+use comma::{
+    cache::{Refresh, Ttl},
+    config::{ConfigFile, ProjectFile},
+    resolver::ResolverSpec,
+    select::Select,
+};
+
+use crate::Opt;
+
+/// Where the effective value of a setting came from
+pub enum Origin {
+    CommandLine,
+    Environment(String),
+    File(PathBuf),
+    Default,
+}
+
+/// The effective value of a setting
+pub struct Setting {
+    pub name: &'static str,
+    pub value: toml::Value,
+    pub origin: Origin,
+}
+
+trait SettingValue {
+    fn to_value(&self) -> toml::Value;
+}
+
+impl SettingValue for String {
+    fn to_value(&self) -> toml::Value {
+        self.as_str().into()
+    }
+}
+
+impl SettingValue for u8 {
+    fn to_value(&self) -> toml::Value {
+        i64::from(*self).into()
+    }
+}
+
+impl SettingValue for bool {
+    fn to_value(&self) -> toml::Value {
+        (*self).into()
+    }
+}
+
+impl SettingValue for Vec<ResolverSpec> {
+    fn to_value(&self) -> toml::Value {
+        self.iter()
+            .map(|r| r.to_string())
+            .collect::<Vec<_>>()
+            .into()
+    }
+}
+
+impl SettingValue for Ttl {
+    fn to_value(&self) -> toml::Value {
+        self.to_string().into()
+    }
+}
+
+impl SettingValue for Refresh {
+    fn to_value(&self) -> toml::Value {
+        self.to_string().into()
+    }
+}
+
+impl SettingValue for Select {
+    fn to_value(&self) -> toml::Value {
+        self.to_string().into()
+    }
+}
+
+fn env_name(id: &str) -> String {
+    Opt::command()
+        .get_arguments()
+        .find(|arg| arg.get_id() == id)
+        .and_then(|arg| arg.get_env())
+        .map(|env| env.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+/// Takes a setting from the first configuration file that has it, unless it
+/// was given on the command line or in the environment
+macro_rules! merge_setting {
+    ($args:expr, $matches:expr, $files:expr, $field:ident) => {{
+        let name = stringify!($field);
+        let origin = match $matches.value_source(name) {
+            Some(ValueSource::CommandLine) => Origin::CommandLine,
+            Some(ValueSource::EnvVariable) => Origin::Environment(env_name(name)),
+            _ => match $files
+                .iter()
+                .find_map(|file| Some((file.config.$field.clone()?, &file.path)))
+            {
+                Some((value, path)) => {
+                    $args.$field = value;
+                    Origin::File(path.clone())
+                }
+                None => Origin::Default,
+            },
+        };
+        Setting {
+            name,
+            value: $args.$field.to_value(),
+            origin,
+        }
+    }};
+}
+
+/// Fill in the settings that were not given on the command line or in the
+/// environment from the configuration files, which are ordered by precedence.
+/// The flake pinned by the `project` file takes precedence over everything
+/// but the command line. Returns the effective value of every setting.
+pub fn merge(
+    args: &mut Opt,
+    matches: &ArgMatches,
+    files: &[ConfigFile],
+    project: Option<&ProjectFile>,
+) -> Vec<Setting> {
+    let mut settings = vec![
+        merge_setting!(args, matches, files, picker),
+        merge_setting!(args, matches, files, nixpkgs_flake),
+        merge_setting!(args, matches, files, cache_level),
+        merge_setting!(args, matches, files, ask),
+        merge_setting!(args, matches, files, resolvers),
+        merge_setting!(args, matches, files, path_ttl),
+        merge_setting!(args, matches, files, refresh),
+        merge_setting!(args, matches, files, gc_roots),
+        merge_setting!(args, matches, files, frozen),
+        merge_setting!(args, matches, files, select),
+    ];
+
+    if let Some(project) = project {
+        pin_project_flake(args, project, &mut settings);
+    }
+
+    settings
+}
+
+/// Use the flake pinned by `project`, whatever the environment or the
+/// configuration files say, so that everyone gets the same packages
+fn pin_project_flake(args: &mut Opt, project: &ProjectFile, settings: &mut [Setting]) {
+    let Some(flake) = &project.config.nixpkgs_flake else {
+        return;
+    };
+    let Some(setting) = settings.iter_mut().find(|s| s.name == "nixpkgs_flake") else {
+        return;
+    };
+
+    match setting.origin {
+        Origin::CommandLine if args.nixpkgs_flake != *flake => eprintln!(
+            "Warning: using nixpkgs flake '{}' from the command line instead of '{flake}' pinned by {}",
+            args.nixpkgs_flake,
+            project.path.display()
+        ),
+        Origin::CommandLine => {}
+        _ => {
+            args.nixpkgs_flake = flake.clone();
+            setting.value = flake.to_value();
+            setting.origin = Origin::File(project.path.clone());
+        }
+    }
+}
+
+/// Print the settings, configured packages and aliases as TOML, with where each
+/// value came from as a comment
+pub fn show(settings: &[Setting], files: &[ConfigFile]) {
+    let mut lines: Vec<_> = settings
+        .iter()
+        .map(|setting| {
+            (
+                format!("{} = {}", setting.name, setting.value),
+                match &setting.origin {
+                    Origin::CommandLine => "command line".to_owned(),
+                    Origin::Environment(var) => format!("environment ({var})"),
+                    Origin::File(path) => path.display().to_string(),
+                    Origin::Default => "default".to_owned(),
+                },
+            )
+        })
+        .collect();
+
+    // Files are ordered by precedence, so the first one to configure a
+    // command wins
+    let mut packages = BTreeMap::new();
+    for file in files {
+        for (command, derivation) in &file.config.packages {
+            packages.entry(command).or_insert((derivation, &file.path));
+        }
+    }
+    if !packages.is_empty() {
+        lines.push((String::new(), String::new()));
+        lines.push(("[packages]".to_owned(), String::new()));
+        lines.extend(packages.into_iter().map(|(command, (derivation, path))| {
+            (
+                format!("{command} = {}", toml::Value::from(derivation.as_str())),
+                path.display().to_string(),
+            )
+        }));
+    }
+
+    let mut aliases = BTreeMap::new();
+    for file in files {
+        for (name, alias) in &file.config.aliases {
+            aliases.entry(name).or_insert((alias, &file.path));
+        }
+    }
+    if !aliases.is_empty() {
+        lines.push((String::new(), String::new()));
+        lines.push(("[aliases]".to_owned(), String::new()));
+        lines.extend(aliases.into_iter().map(|(name, (alias, path))| {
+            let alias = toml::Value::try_from(alias).expect("aliases serialize to TOML");
+            (format!("{name} = {alias}"), path.display().to_string())
+        }));
+    }
+
+    let width = lines
+        .iter()
+        .map(|(line, _)| line.len())
+        .max()
+        .unwrap_or_default();
+
+    for (line, origin) in lines {
+        if origin.is_empty() {
+            println!("{line}");
+        } else {
+            println!("{line:width$}  # {origin}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, time::Duration};
+
+    use clap::FromArgMatches;
+    use comma::config::Config;
+
+    use super::*;
+
+    fn file(path: &str, config: Config) -> ConfigFile {
+        ConfigFile {
+            path: PathBuf::from(path),
+            config,
+        }
+    }
+
+    #[test]
+    fn merges_by_precedence() {
+        // Only this test reads these variables
+        env::set_var("COMMA_PICKER", "env-picker");
+        env::set_var("COMMA_NIXPKGS_FLAKE", "env-flake");
+        env::remove_var("COMMA_PATH_TTL");
+        env::remove_var("COMMA_SELECT");
+        env::remove_var("COMMA_CACHING");
+
+        let matches = Opt::command()
+            .try_get_matches_from(["comma", "--picker", "cli-picker", "hello"])
+            .unwrap();
+        let mut args = Opt::from_arg_matches(&matches).unwrap();
+        let files = [
+            file(
+                "/home/me/.config/comma/config.toml",
+                Config {
+                    picker: Some("user-picker".into()),
+                    nixpkgs_flake: Some("user-flake".into()),
+                    path_ttl: Some(Ttl::After(Duration::from_secs(3600))),
+                    ..Config::default()
+                },
+            ),
+            file(
+                "/etc/comma/config.toml",
+                Config {
+                    picker: Some("system-picker".into()),
+                    nixpkgs_flake: Some("system-flake".into()),
+                    path_ttl: Some(Ttl::Never),
+                    select: Some(Select::First),
+                    ..Config::default()
+                },
+            ),
+        ];
+
+        let settings = merge(&mut args, &matches, &files, None);
+        env::remove_var("COMMA_PICKER");
+        env::remove_var("COMMA_NIXPKGS_FLAKE");
+
+        let origin = |name| match &settings.iter().find(|s| s.name == name).unwrap().origin {
+            Origin::CommandLine => "command line".to_owned(),
+            Origin::Environment(var) => var.clone(),
+            Origin::File(path) => path.display().to_string(),
+            Origin::Default => "default".to_owned(),
+        };
+
+        assert_eq!(args.picker, "cli-picker");
+        assert_eq!(origin("picker"), "command line");
+        assert_eq!(args.nixpkgs_flake, "env-flake");
+        assert_eq!(origin("nixpkgs_flake"), "COMMA_NIXPKGS_FLAKE");
+        assert_eq!(args.path_ttl, Ttl::After(Duration::from_secs(3600)));
+        assert_eq!(origin("path_ttl"), "/home/me/.config/comma/config.toml");
+        assert_eq!(args.select, Select::First);
+        assert_eq!(origin("select"), "/etc/comma/config.toml");
+        assert_eq!(args.cache_level, 2);
+        assert_eq!(origin("cache_level"), "default");
+    }
+}
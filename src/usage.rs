@@ -0,0 +1,201 @@
+//! Local log of the commands run through comma, and of the packages they were
+//! run from.
+//!
+//! Every run appends a line of JSON to the log. Once the log grows past
+//! [`COMPACT_SIZE`], the lines are merged into one per command and package,
+//! and the least frecent ones are dropped to leave room for new runs.
+
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    error::Error,
+    fs::{self, File, OpenOptions},
+    io::{self, BufRead, BufReader, Write},
+    path::PathBuf,
+};
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+
+use crate::cache;
+
+type ResultDyn<T> = Result<T, Box<dyn Error>>;
+
+/// Size in bytes above which the log is compacted
+const COMPACT_SIZE: u64 = 64 * 1024;
+
+const DAY: u64 = 86400;
+
+/// How often a command was run from a package
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Usage {
+    pub command: String,
+    pub attribute: String,
+    pub count: u64,
+    /// When the command was last run, in seconds since the Unix epoch
+    pub last_run: u64,
+}
+
+impl Usage {
+    /// The number of runs, weighted by how recent the last one was
+    pub fn frecency(&self, now: u64) -> u64 {
+        let weight = match now.saturating_sub(self.last_run) {
+            age if age <= DAY => 8,
+            age if age <= 7 * DAY => 4,
+            age if age <= 30 * DAY => 2,
+            _ => 1,
+        };
+        self.count * weight
+    }
+}
+
+/// The usage log in the comma state directory
+pub struct UsageLog {
+    path: PathBuf,
+}
+
+impl UsageLog {
+    pub fn new() -> ResultDyn<Self> {
+        let path = xdg::BaseDirectories::new()?.place_state_file("comma/usage")?;
+        Ok(Self { path })
+    }
+
+    /// Record a run of `command` from the package `attribute`
+    pub fn record(&self, command: &str, attribute: &str) -> ResultDyn<()> {
+        let lock = File::create(self.path.with_extension("lock"))?;
+        lock.lock()?;
+
+        let usage = Usage {
+            command: command.to_owned(),
+            attribute: attribute.to_owned(),
+            count: 1,
+            last_run: cache::now(),
+        };
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        writeln!(file, "{}", serde_json::to_string(&usage)?)?;
+
+        if file.metadata()?.len() > COMPACT_SIZE {
+            self.compact()?;
+        }
+
+        Ok(())
+    }
+
+    /// Rewrite the log with a single line per command and package, keeping
+    /// the most frecent ones within half of [`COMPACT_SIZE`] so that the log
+    /// is not compacted again on the next run
+    fn compact(&self) -> ResultDyn<()> {
+        debug!("compacting usage log: {}", self.path.display());
+
+        let now = cache::now();
+        let mut usages = self.read()?;
+        usages.sort_by_key(|usage| Reverse((usage.frecency(now), usage.last_run)));
+
+        let mut contents = String::new();
+        for usage in usages {
+            let line = serde_json::to_string(&usage)?;
+            if (contents.len() + line.len() + 1) as u64 > COMPACT_SIZE / 2 {
+                break;
+            }
+            contents.push_str(&line);
+            contents.push('\n');
+        }
+
+        let tmp_path = self
+            .path
+            .with_extension(format!("{}.tmp", std::process::id()));
+        fs::write(&tmp_path, contents)?;
+        fs::rename(&tmp_path, &self.path).inspect_err(|_| {
+            let _ = fs::remove_file(&tmp_path);
+        })?;
+
+        Ok(())
+    }
+
+    /// Every command and package that was run, with the number of runs and
+    /// the last one. Lines that cannot be parsed are skipped.
+    pub fn read(&self) -> ResultDyn<Vec<Usage>> {
+        let file = match File::open(&self.path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut usages: HashMap<(String, String), Usage> = HashMap::new();
+        for line in BufReader::new(file).lines() {
+            let line = line?;
+            let usage: Usage = match serde_json::from_str(&line) {
+                Ok(usage) => usage,
+                Err(e) => {
+                    debug!("skipping invalid usage log line '{line}': {e}");
+                    continue;
+                }
+            };
+            let key = (usage.command.clone(), usage.attribute.clone());
+            usages
+                .entry(key)
+                .and_modify(|total| {
+                    total.count += usage.count;
+                    total.last_run = total.last_run.max(usage.last_run);
+                })
+                .or_insert(usage);
+        }
+
+        Ok(usages.into_values().collect())
+    }
+
+    /// The frecency of every package, summed over the commands run from it
+    pub fn frecency(&self) -> ResultDyn<HashMap<String, u64>> {
+        let now = cache::now();
+        let mut frecency = HashMap::new();
+        for usage in self.read()? {
+            *frecency.entry(usage.attribute.clone()).or_default() += usage.frecency(now);
+        }
+        Ok(frecency)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compacts_well_below_the_limit() {
+        let path = std::env::temp_dir().join(format!("comma-{}-usage", std::process::id()));
+        let log = UsageLog { path: path.clone() };
+
+        let mut contents = String::new();
+        for i in 0.. {
+            let usage = Usage {
+                command: format!("command{i}"),
+                attribute: format!("package{i}.out"),
+                count: 1,
+                last_run: 0,
+            };
+            contents.push_str(&serde_json::to_string(&usage).unwrap());
+            contents.push('\n');
+            if contents.len() as u64 > COMPACT_SIZE {
+                break;
+            }
+        }
+        fs::write(&path, contents).unwrap();
+
+        log.record("hello", "hello.out").unwrap();
+        assert!(fs::metadata(&path).unwrap().len() <= COMPACT_SIZE / 2);
+        let usages = log.read().unwrap();
+        assert!(usages.iter().any(|usage| usage.command == "hello"));
+
+        // The next run only appends
+        let size = fs::metadata(&path).unwrap().len();
+        log.record("hello", "hello.out").unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        assert_eq!(contents.lines().count(), usages.len() + 1);
+        assert!(contents.len() as u64 > size);
+
+        fs::remove_file(&path).unwrap();
+        let _ = fs::remove_file(path.with_extension("lock"));
+    }
+}